@@ -0,0 +1,399 @@
+use std::{
+    fs::{File, Metadata},
+    io::{self, Read, Write},
+    path::Path,
+    time::UNIX_EPOCH,
+};
+
+#[cfg(unix)]
+use std::os::unix::fs::MetadataExt;
+
+use flate2::write::GzEncoder;
+use tar::{Builder, Header, HeaderMode};
+use xz2::write::XzEncoder;
+use zip::{write::FullFileOptions, CompressionMethod, DateTime, ZipWriter};
+
+use crate::{config::Config, crypt::Encryption, glob::Glob, time};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Zip,
+    TarZstd,
+    TarXz,
+    TarGzip,
+}
+impl Format {
+    pub fn parse(value: &str) -> Option<Self> {
+        match value {
+            "zip" => Some(Self::Zip),
+            "tar.zst" | "tar.zstd" => Some(Self::TarZstd),
+            "tar.xz" => Some(Self::TarXz),
+            "tar.gz" | "tgz" => Some(Self::TarGzip),
+            _ => None,
+        }
+    }
+
+    pub fn extension(&self) -> &'static str {
+        match self {
+            Self::Zip => "zip",
+            Self::TarZstd => "tar.zst",
+            Self::TarXz => "tar.xz",
+            Self::TarGzip => "tar.gz",
+        }
+    }
+
+    /// Command to unpack an archive of this format.
+    pub fn unpack_command(&self) -> &'static str {
+        match self {
+            Self::Zip => "unzip",
+            Self::TarZstd => "tar --zstd -xpf",
+            Self::TarXz => "tar -xJpf",
+            Self::TarGzip => "tar -xzpf",
+        }
+    }
+
+    /// Guess the format from the first bytes of an archive.
+    pub fn detect(magic: &[u8]) -> Option<Self> {
+        if magic.starts_with(b"PK\x03\x04") || magic.starts_with(b"PK\x05\x06") {
+            Some(Self::Zip)
+        } else if magic.starts_with(&[0x28, 0xb5, 0x2f, 0xfd]) {
+            Some(Self::TarZstd)
+        } else if magic.starts_with(&[0xfd, b'7', b'z', b'X', b'Z', 0x00]) {
+            Some(Self::TarXz)
+        } else if magic.starts_with(&[0x1f, 0x8b]) {
+            Some(Self::TarGzip)
+        } else {
+            None
+        }
+    }
+}
+
+/// How to compress zip entries matching a pattern.
+#[derive(Debug, Clone)]
+pub struct CompressRule {
+    glob: Glob,
+    method: Compression,
+    level: Option<i64>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Compression {
+    Method(CompressionMethod),
+    /// Store data that does not compress well, deflate everything else.
+    Auto,
+}
+
+impl CompressRule {
+    /// Parse `<pattern> <method> [level]`, a pattern starting with `.` matches
+    /// files with that extension.
+    pub fn parse(value: &str) -> Result<Self, &'static str> {
+        let mut parts = value.split_whitespace();
+        let (Some(pattern), Some(method)) = (parts.next(), parts.next()) else {
+            return Err("expected 'compress <pattern> <method> [level]'");
+        };
+
+        let method = match method {
+            "store" => Compression::Method(CompressionMethod::Stored),
+            "deflate" => Compression::Method(CompressionMethod::Deflated),
+            "bzip2" => Compression::Method(CompressionMethod::Bzip2),
+            "zstd" => Compression::Method(CompressionMethod::Zstd),
+            "auto" => Compression::Auto,
+            "deflate64" => return Err("deflate64 can only be extracted, not created"),
+            _ => return Err("unknown method, expected store, deflate, bzip2, zstd or auto"),
+        };
+
+        let level = match parts.next() {
+            Some(x) => Some(x.parse().map_err(|_| "invalid compression level")?),
+            None => None,
+        };
+        if parts.next().is_some() {
+            return Err("expected 'compress <pattern> <method> [level]'");
+        }
+        let range = match method {
+            Compression::Method(CompressionMethod::Stored) => 0..=0,
+            Compression::Method(CompressionMethod::Bzip2) => 1..=9,
+            Compression::Method(CompressionMethod::Zstd) => -7..=22,
+            _ => 0..=264,
+        };
+        if level.is_some_and(|x| !range.contains(&x)) {
+            return Err("compression level out of range for this method");
+        }
+
+        let glob = match pattern.strip_prefix('.') {
+            Some(x) if !x.contains(['/', '*', '?', '[']) => Glob::new(&format!("*.{x}")),
+            _ => Glob::new(pattern),
+        };
+
+        Ok(Self {
+            glob,
+            method,
+            level,
+        })
+    }
+}
+
+/// Bytes sampled from the start of a file to guess whether it compresses.
+const SAMPLE_SIZE: u64 = 64 * 1024;
+
+/// Whether a sample looks already compressed, e.g. media or archives.
+fn incompressible(sample: &[u8]) -> bool {
+    // Too small to tell, and cheap to compress anyway
+    if sample.len() < 4096 {
+        return false;
+    }
+
+    let mut encoder = flate2::write::DeflateEncoder::new(
+        Vec::with_capacity(sample.len()),
+        flate2::Compression::fast(),
+    );
+    match encoder.write_all(sample).and_then(|_| encoder.finish()) {
+        Ok(x) => x.len() * 100 >= sample.len() * 95,
+        Err(_) => false,
+    }
+}
+
+/// Archive being built from the backed up files.
+pub trait ArchiveWriter {
+    fn add_file(
+        &mut self,
+        name: &str,
+        metadata: &Metadata,
+        contents: &mut dyn Read,
+    ) -> io::Result<()>;
+    fn add_symlink(&mut self, name: &str, metadata: &Metadata, target: &Path) -> io::Result<()>;
+    fn add_directory(&mut self, name: &str, metadata: &Metadata) -> io::Result<()>;
+    fn finish(self: Box<Self>) -> io::Result<()>;
+}
+
+/// Create an archive writer for the configured format.
+pub fn create(config: &Config, file: File) -> io::Result<Box<dyn ArchiveWriter + '_>> {
+    Ok(match config.format {
+        Format::Zip => Box::new(Zip {
+            zip: ZipWriter::new(file),
+            rules: &config.compress,
+            level: config.compression_level,
+            options: {
+                let options = FullFileOptions::default()
+                    .compression_level(Some(config.compression_level))
+                    .compression_method(CompressionMethod::Deflated);
+
+                if let (Some(x), Encryption::Entries) = (&config.password, config.encryption) {
+                    options.with_aes_encryption(zip::AesMode::Aes256, x)
+                } else {
+                    options
+                }
+            },
+        }),
+        Format::TarZstd => {
+            let mut encoder =
+                zstd::Encoder::new(file, config.compression_level.clamp(1, 22) as i32)?;
+            encoder.multithread(
+                std::thread::available_parallelism()
+                    .map(|x| x.get() as u32)
+                    .unwrap_or(1),
+            )?;
+            Box::new(Tar(Builder::new(Compressor::Zstd(encoder))))
+        }
+        Format::TarXz => Box::new(Tar(Builder::new(Compressor::Xz(XzEncoder::new(
+            file,
+            config.compression_level.clamp(0, 9) as u32,
+        ))))),
+        Format::TarGzip => Box::new(Tar(Builder::new(Compressor::Gzip(GzEncoder::new(
+            file,
+            flate2::Compression::new(config.compression_level.clamp(0, 9) as u32),
+        ))))),
+    })
+}
+
+struct Zip<'a> {
+    zip: ZipWriter<File>,
+    rules: &'a [CompressRule],
+    level: i64,
+    options: FullFileOptions<'a>,
+}
+impl<'a> Zip<'a> {
+    /// Options for an entry carrying over metadata of the original file.
+    fn options(&self, metadata: &Metadata) -> FullFileOptions<'a> {
+        let mut options = self.options.clone();
+
+        if let Some(mtime) = metadata
+            .modified()
+            .ok()
+            .and_then(|x| x.duration_since(UNIX_EPOCH).ok())
+            .map(|x| x.as_secs())
+        {
+            let (year, month, day, hour, minute, second) = time::civil(mtime as i64);
+            if let Ok(x) = DateTime::from_date_and_time(
+                year.clamp(1980, 2107) as u16,
+                month as u8,
+                day as u8,
+                hour as u8,
+                minute as u8,
+                second as u8,
+            ) {
+                options = options.last_modified_time(x);
+            }
+
+            // Extended timestamp
+            if let Ok(mtime) = u32::try_from(mtime) {
+                let mut data = vec![1u8];
+                data.extend(mtime.to_le_bytes());
+                let _ = options.add_extra_data(0x5455, data.into_boxed_slice(), false);
+            }
+        }
+
+        #[cfg(unix)]
+        {
+            options = options.unix_permissions(metadata.mode());
+
+            // Info-ZIP Unix (uid and gid)
+            let mut data = vec![1u8, 4];
+            data.extend(metadata.uid().to_le_bytes());
+            data.push(4);
+            data.extend(metadata.gid().to_le_bytes());
+            let _ = options.add_extra_data(0x7875, data.into_boxed_slice(), false);
+        }
+
+        options
+    }
+}
+impl ArchiveWriter for Zip<'_> {
+    fn add_file(
+        &mut self,
+        name: &str,
+        metadata: &Metadata,
+        contents: &mut dyn Read,
+    ) -> io::Result<()> {
+        let mut options = self
+            .options(metadata)
+            .large_file(metadata.len() >= 1024 * 1024 * 1024 * 4);
+
+        // Later rules take precedence
+        let mut sample = vec![];
+        match self.rules.iter().rev().find(|x| x.glob.matches(name)) {
+            Some(CompressRule {
+                method: Compression::Method(method),
+                level,
+                ..
+            }) => {
+                options = options
+                    .compression_method(*method)
+                    .compression_level(match method {
+                        CompressionMethod::Stored => None,
+                        CompressionMethod::Deflated => Some(level.unwrap_or(self.level)),
+                        _ => *level,
+                    });
+            }
+            Some(CompressRule {
+                method: Compression::Auto,
+                level,
+                ..
+            }) => {
+                contents.take(SAMPLE_SIZE).read_to_end(&mut sample)?;
+                options = if incompressible(&sample) {
+                    options
+                        .compression_method(CompressionMethod::Stored)
+                        .compression_level(None)
+                } else {
+                    options.compression_level(Some(level.unwrap_or(self.level)))
+                };
+            }
+            None => (),
+        }
+
+        self.zip.start_file(name, options)?;
+        io::copy(&mut sample.as_slice().chain(contents), &mut self.zip)?;
+        Ok(())
+    }
+
+    fn add_symlink(&mut self, name: &str, metadata: &Metadata, target: &Path) -> io::Result<()> {
+        let options = self.options(metadata);
+        self.zip
+            .add_symlink(name, target.to_string_lossy(), options)?;
+        Ok(())
+    }
+
+    fn add_directory(&mut self, name: &str, metadata: &Metadata) -> io::Result<()> {
+        let options = self.options(metadata);
+        self.zip.add_directory(name, options)?;
+        Ok(())
+    }
+
+    fn finish(self: Box<Self>) -> io::Result<()> {
+        self.zip.finish()?;
+        Ok(())
+    }
+}
+
+enum Compressor {
+    Zstd(zstd::Encoder<'static, File>),
+    Xz(XzEncoder<File>),
+    Gzip(GzEncoder<File>),
+}
+impl Compressor {
+    fn finish(self) -> io::Result<File> {
+        match self {
+            Self::Zstd(x) => x.finish(),
+            Self::Xz(x) => x.finish(),
+            Self::Gzip(x) => x.finish(),
+        }
+    }
+}
+impl Write for Compressor {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Self::Zstd(x) => x.write(buf),
+            Self::Xz(x) => x.write(buf),
+            Self::Gzip(x) => x.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Self::Zstd(x) => x.flush(),
+            Self::Xz(x) => x.flush(),
+            Self::Gzip(x) => x.flush(),
+        }
+    }
+}
+
+struct Tar(Builder<Compressor>);
+impl Tar {
+    fn header(metadata: &Metadata) -> Header {
+        let mut header = Header::new_gnu();
+        header.set_metadata_in_mode(metadata, HeaderMode::Complete);
+        header
+    }
+}
+impl ArchiveWriter for Tar {
+    fn add_file(
+        &mut self,
+        name: &str,
+        metadata: &Metadata,
+        contents: &mut dyn Read,
+    ) -> io::Result<()> {
+        let mut header = Self::header(metadata);
+        let size = header.size()?;
+
+        // Files may change while being read, the entry must still be exactly
+        // as large as the header says
+        let contents = contents.take(size).chain(io::repeat(0)).take(size);
+        self.0.append_data(&mut header, name, contents)
+    }
+
+    fn add_symlink(&mut self, name: &str, metadata: &Metadata, target: &Path) -> io::Result<()> {
+        let mut header = Self::header(metadata);
+        header.set_size(0);
+        self.0.append_link(&mut header, name, target)
+    }
+
+    fn add_directory(&mut self, name: &str, metadata: &Metadata) -> io::Result<()> {
+        let mut header = Self::header(metadata);
+        header.set_size(0);
+        self.0.append_data(&mut header, name, io::empty())
+    }
+
+    fn finish(self: Box<Self>) -> io::Result<()> {
+        self.0.into_inner()?.finish()?.flush()
+    }
+}
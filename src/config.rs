@@ -1,17 +1,124 @@
 use std::fmt::Write;
-use std::{fs, process::exit, time::Duration};
+use std::{fs, net::SocketAddr, path::PathBuf, process::exit, sync::Arc, time::Duration};
 
-use crate::hook::Webhook;
+use crate::{
+    archive::{CompressRule, Format},
+    control,
+    crypt::Encryption,
+    glob::Ignore,
+    healthcheck::{self, Healthchecks},
+    hook::Webhook,
+    http::{self, Backend, Proxy},
+    lock::LockMode,
+    log::{self, Color, Level},
+    manifest,
+    secret::Secret,
+    throttle::{Limit, Throttle, Window},
+};
+
+#[derive(Debug)]
+pub struct Script {
+    pub shell: Vec<String>,
+    pub body: String,
+}
 
 #[derive(Debug)]
 pub struct Config {
+    /// Where the config was read from, for reloading.
+    pub path: String,
+    pub name: String,
+    /// Identifies the job when locking, the job name if one is set and the
+    /// config path otherwise.
+    pub lock_key: String,
     pub webhook: Webhook,
-    pub script: String,
-    pub shell: Vec<String>,
+    pub webhook_source: Secret,
+    pub script: Option<Script>,
+    pub include: Vec<(PathBuf, String)>,
+    pub exclude: Ignore,
+    pub max_file_size: Option<u64>,
+    pub pre: Option<Script>,
+    pub post_success: Option<Script>,
+    pub post_failure: Option<Script>,
     pub delay: Duration,
     pub password: Option<String>,
+    pub password_source: Option<Secret>,
+    pub encryption: Encryption,
+    pub recipients: Vec<age::x25519::Recipient>,
+    pub signing_key: Option<ed25519_dalek::SigningKey>,
+    pub signing_key_source: Option<Secret>,
+    pub verify_key: Option<ed25519_dalek::VerifyingKey>,
+    pub format: Format,
     pub compression_level: i64,
+    pub compress: Vec<CompressRule>,
     pub block_size: u8,
+    pub script_log_size: usize,
+    pub archive_script_log: bool,
+    pub env: Vec<(String, String)>,
+    pub env_files: Vec<PathBuf>,
+    pub temp_dir: Option<PathBuf>,
+    /// Where the run count and last success are kept, see [crate::state].
+    pub state_file: Option<PathBuf>,
+    pub lock: LockMode,
+    pub control_socket: Option<PathBuf>,
+    pub metrics_file: Option<PathBuf>,
+    pub metrics_listen: Option<SocketAddr>,
+    pub healthchecks: Healthchecks,
+    pub log: log::Options,
+    pub http: http::Options,
+}
+
+impl Config {
+    /// Read the webhook and password again, in case they are references
+    /// to values that change.
+    pub fn resolve_secrets(&mut self) -> Result<(), String> {
+        self.webhook = Webhook::new(
+            self.webhook_source
+                .resolve()
+                .map_err(|why| format!("failed to resolve webhook: {why}"))?,
+            self.http
+                .client()
+                .map_err(|why| format!("failed to set up http client: {why}"))?,
+        );
+        self.signing_key = match &self.signing_key_source {
+            Some(x) => Some(
+                x.resolve()
+                    .and_then(|x| manifest::signing_key(&x).map_err(String::from))
+                    .map_err(|why| format!("failed to resolve signing key: {why}"))?,
+            ),
+            None => None,
+        };
+        self.resolve_password()
+    }
+
+    /// Read the password again, restoring doesn't need the webhook.
+    pub fn resolve_password(&mut self) -> Result<(), String> {
+        self.password = match &self.password_source {
+            Some(x) => Some(
+                x.resolve()
+                    .map_err(|why| format!("failed to resolve password: {why}"))?,
+            ),
+            None => None,
+        };
+        Ok(())
+    }
+}
+
+/// What to do with the parsed config.
+pub enum Mode {
+    /// Back up periodically.
+    Backup,
+    /// Back up once and exit.
+    Once,
+    /// Extract a downloaded backup archive.
+    Restore {
+        archive: PathBuf,
+        destination: PathBuf,
+        identity: Option<PathBuf>,
+        /// Restore manifests even if no verify-key is set.
+        no_verify: bool,
+    },
+    /// Talk to a running daemon.
+    Control(control::Command),
 }
 
 struct TimeColumn {
@@ -53,18 +160,163 @@ const TIME_TABLE: &[TimeColumn] = &[
     ),
 ];
 
-pub fn parse_args() -> Config {
+struct SizeColumn {
+    pub aliases: &'static [&'static str],
+    pub size: u64,
+}
+impl SizeColumn {
+    pub const fn new(aliases: &'static [&'static str], size: u64) -> Self {
+        Self { aliases, size }
+    }
+}
+
+const SIZE_TABLE: &[SizeColumn] = &[
+    SizeColumn::new(&["", "b", "B", "byte", "bytes"], 1),
+    SizeColumn::new(&["k", "K", "KB", "kB"], 1000),
+    SizeColumn::new(&["KiB", "kiB"], 1024),
+    SizeColumn::new(&["M", "MB"], 1000 * 1000),
+    SizeColumn::new(&["MiB"], 1024 * 1024),
+    SizeColumn::new(&["G", "GB"], 1000 * 1000 * 1000),
+    SizeColumn::new(&["GiB"], 1024 * 1024 * 1024),
+    SizeColumn::new(&["T", "TB"], 1000 * 1000 * 1000 * 1000),
+    SizeColumn::new(&["TiB"], 1024 * 1024 * 1024 * 1024),
+];
+
+/// Parse a size such as `512`, `64KiB` or `2 GB` into bytes.
+fn parse_size(value: &str) -> Option<u64> {
+    let value = value.trim();
+    let split = value
+        .find(|x: char| !x.is_ascii_digit())
+        .unwrap_or(value.len());
+    let (value, unit) = value.split_at(split);
+    let value: u64 = value.parse().ok()?;
+    let unit = SIZE_TABLE
+        .iter()
+        .find(|x| x.aliases.contains(&unit.trim()))?;
+    value.checked_mul(unit.size)
+}
+
+/// Parse a duration such as `30s`, `2 min`, `1m 30s` or `day`.
+fn parse_duration(value: &str) -> Option<Duration> {
+    let mut duration = Duration::ZERO;
+    let mut iter = value.split_whitespace();
+    while let Some(x) = iter.next() {
+        let split = x.find(|x: char| !x.is_ascii_digit()).unwrap_or(x.len());
+        let (value, unit) = x.split_at(split);
+        // A unit on its own counts once
+        let value: u32 = match value {
+            "" => 1,
+            x => x.parse().ok()?,
+        };
+        let unit = match unit {
+            "" => iter.next()?,
+            x => x,
+        };
+        let unit = TIME_TABLE.iter().find(|x| x.aliases.contains(&unit))?;
+        duration += unit.time.checked_mul(value)?;
+    }
+    Some(duration)
+}
+
+pub fn parse_args() -> (Config, Mode) {
     let mut args = std::env::args();
     let exe = args.next().unwrap_or("discord-backup-util".into());
     let mut config = args.next().unwrap_or("backup_config".into());
 
     let mut setup = false;
+    let mut mode = Mode::Backup;
+    let mut identity = None;
+    let mut no_verify = false;
+    let mut overrides = log::Overrides::default();
+
+    if config == "ctl" {
+        let command = match args.next().as_deref() {
+            Some("status") => Some(control::Command::Status),
+            Some("trigger") => args.next().map(control::Command::Trigger),
+            Some("cancel") => Some(control::Command::Cancel),
+            Some("next") => Some(control::Command::Next),
+            _ => None,
+        };
+        let Some(command) = command else {
+            eprintln!("{exe}: usage: {exe} ctl status|trigger <job>|cancel|next [config]");
+            exit(-1);
+        };
+        mode = Mode::Control(command);
+        config = args.next().unwrap_or("backup_config".into());
+    }
 
     while config.starts_with("--") {
         if config == "--setup" {
             setup = true;
         }
 
+        if config == "--restore" {
+            let (Some(archive), Some(destination)) = (args.next(), args.next()) else {
+                eprintln!("{exe}: usage: {exe} --restore <archive> <directory> [config]");
+                exit(-1);
+            };
+            mode = Mode::Restore {
+                archive: archive.into(),
+                destination: destination.into(),
+                identity: None,
+                no_verify: false,
+            };
+        }
+
+        if config == "--once" {
+            mode = Mode::Once;
+        }
+
+        if config == "--keygen" {
+            let (secret, public) = manifest::keygen();
+            println!("signing-key {secret}\nverify-key {public}");
+            exit(0);
+        }
+
+        if config == "--identity" {
+            let Some(x) = args.next() else {
+                eprintln!("{exe}: usage: {exe} --restore <archive> <directory> --identity <file> [config]");
+                exit(-1);
+            };
+            identity = Some(PathBuf::from(x));
+        }
+
+        if config == "--no-verify" {
+            no_verify = true;
+        }
+
+        if config == "--log-level" {
+            let Some(x) = args.next().as_deref().and_then(Level::parse) else {
+                eprintln!("{exe}: usage: {exe} --log-level error|warn|info|debug|trace [config]");
+                exit(-1);
+            };
+            overrides.level = Some(x);
+        }
+
+        if config == "--log-format" {
+            let Some(x) = args.next().as_deref().and_then(log::Format::parse) else {
+                eprintln!("{exe}: usage: {exe} --log-format text|json [config]");
+                exit(-1);
+            };
+            overrides.format = Some(x);
+        }
+
+        if config == "--log-color" {
+            let Some(x) = args.next().as_deref().and_then(Color::parse) else {
+                eprintln!("{exe}: usage: {exe} --log-color auto|always|never [config]");
+                exit(-1);
+            };
+            overrides.color = Some(x);
+        }
+
+        if config == "--log-file" {
+            let Some(x) = args.next() else {
+                eprintln!("{exe}: usage: {exe} --log-file <file> [config]");
+                exit(-1);
+            };
+            overrides.file = Some(PathBuf::from(x));
+        }
+
         config = args.next().unwrap_or("backup_config".into());
 
         if config == "--" {
@@ -72,6 +324,28 @@ pub fn parse_args() -> Config {
         }
     }
 
+    match &mut mode {
+        Mode::Restore {
+            identity: x,
+            no_verify: y,
+            ..
+        } => {
+            *x = identity;
+            *y = no_verify;
+        }
+        Mode::Backup | Mode::Once | Mode::Control(_) if identity.is_some() => {
+            eprintln!("{exe}: --identity can only be used with --restore");
+            exit(-1);
+        }
+        Mode::Backup | Mode::Once | Mode::Control(_) if no_verify => {
+            eprintln!("{exe}: --no-verify can only be used with --restore");
+            exit(-1);
+        }
+        Mode::Backup | Mode::Once | Mode::Control(_) => (),
+    }
+
+    log::set_overrides(overrides);
+
     if setup {
         if let Err(why) = fs::write(&config, include_str!("../backup_config")) {
             println!("{exe}: failed to write to config file {config:?}\n\n{why}");
@@ -80,11 +354,23 @@ pub fn parse_args() -> Config {
         exit(1);
     }
 
+    match load(&config, &mode) {
+        Ok(x) => (x, mode),
+        Err(why) => {
+            eprintln!("{exe}: {why}");
+            exit(-1);
+        }
+    }
+}
+
+/// Read the config file at `path`.
+pub fn load(path: &str, mode: &Mode) -> Result<Config, String> {
+    let config = path.to_string();
+
     let file = match fs::read_to_string(&config) {
         Ok(x) => x,
         Err(why) => {
-            eprintln!("{exe}: failed to read config file {config:?}\n\n{why}");
-            exit(-1);
+            return Err(format!("failed to read config file {config:?}\n\n{why}"));
         }
     };
 
@@ -94,7 +380,42 @@ pub fn parse_args() -> Config {
     let mut delay = None;
     let mut password = None;
     let mut compression = None;
+    let mut format = None;
     let mut block_size = None;
+    let mut script_log_size = None;
+    let mut archive_script_log = false;
+    let mut name = None;
+    let mut env = vec![];
+    let mut env_files = vec![];
+    let mut include = vec![];
+    let mut exclude = Ignore::default();
+    let mut compress = vec![];
+    let mut encryption = None;
+    let mut recipients = vec![];
+    let mut signing_key = None;
+    let mut temp_dir = None;
+    let mut state_file = None;
+    let mut lock = None;
+    let mut control_socket = None;
+    let mut metrics_file = None;
+    let mut metrics_listen = None;
+    let mut healthchecks = vec![];
+    let mut log_level = None;
+    let mut log_format = None;
+    let mut log_color = None;
+    let mut log_file = None;
+    let mut log_max_size = None;
+    let mut log_keep = None;
+    let mut http_backend = None;
+    let mut http_timeout = None;
+    let mut proxy = None;
+    let mut ca_file = None;
+    let mut bandwidth_limits = vec![];
+    let mut verify_key = None;
+    let mut max_file_size = None;
+    let mut pre = None;
+    let mut post_success = None;
+    let mut post_failure = None;
 
     while let Some(x) = lines.peek() {
         let x = x.trim();
@@ -109,150 +430,686 @@ pub fn parse_args() -> Config {
             continue;
         }
 
-        if x.starts_with("password ") {
-            if password
-                .replace(x.split_once(' ').unwrap().1.to_string())
-                .is_some()
+        if let Some(secret) = Secret::parse(x, "password") {
+            if password.replace(secret).is_some() {
+                return Err("cannot set multiple passwords".into());
+            }
+            continue;
+        }
+
+        if x.starts_with("temp-dir ") {
+            let path = PathBuf::from(x.split_once(' ').unwrap().1.trim());
+            if !path.is_dir() {
+                return Err(format!("temp dir {path:?} is not a directory"));
+            }
+            if temp_dir.replace(path).is_some() {
+                return Err("cannot set multiple temp dirs".into());
+            }
+            continue;
+        }
+
+        if x.starts_with("state-file ") {
+            let path = PathBuf::from(x.split_once(' ').unwrap().1.trim());
+            if state_file.replace(path).is_some() {
+                return Err("cannot set multiple state files".into());
+            }
+            continue;
+        }
+
+        if x.starts_with("lock ") {
+            let value = x.split_once(' ').unwrap().1.trim();
+            let Some(mode) = LockMode::parse(value) else {
+                return Err(format!(
+                    "unknown lock mode {value:?}, expected fail, wait or off"
+                ));
+            };
+            if lock.replace(mode).is_some() {
+                return Err("cannot set multiple lock modes".into());
+            }
+            continue;
+        }
+
+        if x.starts_with("control-socket ") {
+            if cfg!(windows) {
+                return Err("control-socket is not supported on Windows".into());
+            }
+            let path = PathBuf::from(x.split_once(' ').unwrap().1.trim());
+            if control_socket.replace(path).is_some() {
+                return Err("cannot set multiple control sockets".into());
+            }
+            continue;
+        }
+
+        if x.starts_with("metrics-file ") {
+            let path = PathBuf::from(x.split_once(' ').unwrap().1.trim());
+            if !path
+                .parent()
+                .is_some_and(|x| x.as_os_str().is_empty() || x.is_dir())
             {
-                eprintln!("{exe}: cannot set multiple passwords");
-                exit(-1);
+                return Err(format!("metrics file {path:?} is not in a directory"));
+            }
+            if metrics_file.replace(path).is_some() {
+                return Err("cannot set multiple metrics files".into());
+            }
+            continue;
+        }
+
+        if x.starts_with("metrics-listen ") {
+            let value = x.split_once(' ').unwrap().1.trim();
+            let Ok(address) = value.parse::<SocketAddr>() else {
+                return Err(format!(
+                    "failed to parse metrics address {value:?}, expected e.g. 127.0.0.1:9469"
+                ));
+            };
+            if metrics_listen.replace(address).is_some() {
+                return Err("cannot set multiple metrics addresses".into());
+            }
+            continue;
+        }
+
+        if x.starts_with("healthcheck-url ") {
+            let value = x.split_once(' ').unwrap().1.trim();
+            let (event, url) = match value.split_once(' ') {
+                Some((event, url)) => match healthcheck::Event::parse(event) {
+                    Some(event) => (Some(event), url.trim()),
+                    None => {
+                        return Err(format!(
+                            "unknown healthcheck event {event:?}, expected start, success or failure"
+                        ));
+                    }
+                },
+                None => (None, value),
+            };
+            if !url.starts_with("http://") && !url.starts_with("https://") {
+                return Err("healthcheck url must start with http:// or https://".into());
+            }
+            healthchecks.push(healthcheck::Check {
+                url: url.to_string(),
+                event,
+            });
+            continue;
+        }
+
+        if x.starts_with("log-level ") {
+            let value = x.split_once(' ').unwrap().1.trim();
+            let Some(level) = Level::parse(value) else {
+                return Err(format!(
+                    "unknown log level {value:?}, expected error, warn, info, debug or trace"
+                ));
+            };
+            if log_level.replace(level).is_some() {
+                return Err("cannot set multiple log levels".into());
+            }
+            continue;
+        }
+
+        if x.starts_with("log-format ") {
+            let value = x.split_once(' ').unwrap().1.trim();
+            let Some(format) = log::Format::parse(value) else {
+                return Err(format!(
+                    "unknown log format {value:?}, expected text or json"
+                ));
+            };
+            if log_format.replace(format).is_some() {
+                return Err("cannot set multiple log formats".into());
+            }
+            continue;
+        }
+
+        if x.starts_with("log-color ") {
+            let value = x.split_once(' ').unwrap().1.trim();
+            let Some(color) = Color::parse(value) else {
+                return Err(format!(
+                    "unknown log color {value:?}, expected auto, always or never"
+                ));
+            };
+            if log_color.replace(color).is_some() {
+                return Err("cannot set multiple log colors".into());
+            }
+            continue;
+        }
+
+        if x.starts_with("log-file ") {
+            let path = PathBuf::from(x.split_once(' ').unwrap().1.trim());
+            if !path
+                .parent()
+                .is_some_and(|x| x.as_os_str().is_empty() || x.is_dir())
+            {
+                return Err(format!("log file {path:?} is not in a directory"));
+            }
+            if log_file.replace(path).is_some() {
+                return Err("cannot set multiple log files".into());
+            }
+            continue;
+        }
+
+        if x.starts_with("log-max-size ") {
+            let Some(value) = parse_size(x.split_once(' ').unwrap().1).filter(|x| *x > 0) else {
+                return Err("invalid log max size".into());
+            };
+            if log_max_size.replace(value).is_some() {
+                return Err("cannot set multiple log max sizes".into());
+            }
+            continue;
+        }
+
+        if x.starts_with("log-keep ") {
+            let Ok(value) = x.split_once(' ').unwrap().1.trim().parse::<usize>() else {
+                return Err("invalid log keep count".into());
+            };
+            if log_keep.replace(value).is_some() {
+                return Err("cannot set multiple log keep counts".into());
+            }
+            continue;
+        }
+
+        if x.starts_with("http-backend ") {
+            let value = x.split_once(' ').unwrap().1.trim();
+            let Some(backend) = Backend::parse(value) else {
+                return Err(format!("unknown or disabled http backend {value:?}"));
+            };
+            if http_backend.replace(backend).is_some() {
+                return Err("cannot set multiple http backends".into());
+            }
+            continue;
+        }
+
+        if x.starts_with("http-timeout ") {
+            let Some(timeout) =
+                parse_duration(x.split_once(' ').unwrap().1).filter(|x| !x.is_zero())
+            else {
+                return Err("failed to parse http timeout: expected a duration such as 30s".into());
+            };
+            if http_timeout.replace(timeout).is_some() {
+                return Err("cannot set multiple http timeouts".into());
+            }
+            continue;
+        }
+
+        if x.starts_with("proxy ") {
+            let value = x.split_once(' ').unwrap().1.trim();
+            let value = (value != "none").then(|| Proxy::new(value));
+            if proxy.replace(value).is_some() {
+                return Err("cannot set multiple proxies".into());
             }
             continue;
         }
 
+        if x.starts_with("bandwidth-limit ") {
+            let mut parts = x.split_whitespace().skip(1);
+            let Some(rate) = parts
+                .next()
+                .and_then(|x| x.strip_suffix("/s"))
+                .and_then(parse_size)
+                .filter(|x| *x > 0)
+            else {
+                return Err(
+                    "failed to parse bandwidth limit: expected a rate such as 2MiB/s".into(),
+                );
+            };
+            let window = match parts.next() {
+                Some(_) if cfg!(windows) => {
+                    return Err("bandwidth-limit time ranges are not supported on Windows, where local time isn't known".into());
+                }
+                Some(x) => match Window::parse(x) {
+                    Some(x) => Some(x),
+                    None => {
+                        return Err("failed to parse bandwidth limit: expected a time range such as 08:00-18:00".into());
+                    }
+                },
+                None => None,
+            };
+            bandwidth_limits.push(Limit { rate, window });
+            continue;
+        }
+
+        if x.starts_with("ca-file ") {
+            let path = PathBuf::from(x.split_once(' ').unwrap().1.trim());
+            if !path.is_file() {
+                return Err(format!("ca file {path:?} does not exist"));
+            }
+            if ca_file.replace(path).is_some() {
+                return Err("cannot set multiple ca files".into());
+            }
+            continue;
+        }
+
+        if let Some(secret) = Secret::parse(x, "signing-key") {
+            if signing_key.replace(secret).is_some() {
+                return Err("cannot set multiple signing keys".into());
+            }
+            continue;
+        }
+
+        if x.starts_with("verify-key ") {
+            match manifest::verify_key(x.split_once(' ').unwrap().1) {
+                Ok(x) => {
+                    if verify_key.replace(x).is_some() {
+                        return Err("cannot set multiple verify keys".into());
+                    }
+                }
+                Err(why) => {
+                    return Err(format!("invalid verify key: {why}"));
+                }
+            }
+            continue;
+        }
+
+        if x.starts_with("recipient ") {
+            match x.split_once(' ').unwrap().1.trim().parse() {
+                Ok(x) => recipients.push(x),
+                Err(why) => {
+                    return Err(format!("invalid recipient: {why}"));
+                }
+            }
+            continue;
+        }
+
+        if x.starts_with("encryption ") {
+            if let Some(value) = Encryption::parse(x.split_once(' ').unwrap().1.trim()) {
+                if encryption.replace(value).is_some() {
+                    return Err("cannot set multiple encryption modes".into());
+                }
+                continue;
+            } else {
+                return Err("invalid encryption mode, expected entries or archive".into());
+            }
+        }
+
         if x.starts_with("compression ") {
             if let Ok(value) = x.split_once(' ').unwrap().1.parse::<i64>() {
                 if compression.replace(value).is_some() {
-                    eprintln!("{exe}: cannot set multiple compression levels");
-                    exit(-1);
+                    return Err("cannot set multiple compression levels".into());
                 }
                 continue;
             } else {
-                eprintln!("{exe}: invalid compression value");
-                exit(-1);
+                return Err("invalid compression value".into());
+            }
+        }
+
+        if x.starts_with("compress ") {
+            match CompressRule::parse(x.split_once(' ').unwrap().1) {
+                Ok(x) => compress.push(x),
+                Err(why) => {
+                    return Err(format!("invalid compress directive: {why}"));
+                }
+            }
+            continue;
+        }
+
+        if x.starts_with("format ") {
+            if let Some(value) = Format::parse(x.split_once(' ').unwrap().1.trim()) {
+                if format.replace(value).is_some() {
+                    return Err("cannot set multiple archive formats".into());
+                }
+                continue;
+            } else {
+                return Err(
+                    "invalid archive format, expected zip, tar.zst, tar.xz or tar.gz".into(),
+                );
             }
         }
 
         if x.starts_with("block-size ") {
             if let Ok(value) = x.split_once(' ').unwrap().1.parse::<u8>() {
                 if block_size.replace(value).is_some() {
-                    eprintln!("{exe}: cannot set multiple block sizes");
-                    exit(-1);
+                    return Err("cannot set multiple block sizes".into());
                 }
                 continue;
             } else {
-                eprintln!("{exe}: invalid block size");
-                exit(-1);
+                return Err("invalid block size".into());
             }
         }
 
-        if x.starts_with("webhook ") {
-            if webhook
-                .replace(Webhook::new(x.split_once(' ').unwrap().1.to_string()))
+        if x.starts_with("script-log-size ") {
+            if let Some(value) = parse_size(x.split_once(' ').unwrap().1) {
+                if script_log_size.replace(value as usize).is_some() {
+                    return Err("cannot set multiple script log sizes".into());
+                }
+                continue;
+            } else {
+                return Err("invalid script log size".into());
+            }
+        }
+
+        if x == "archive-script-log" {
+            archive_script_log = true;
+            continue;
+        }
+
+        if x.starts_with("name ") {
+            if name
+                .replace(x.split_once(' ').unwrap().1.trim().to_string())
                 .is_some()
             {
-                eprintln!("{exe}: cannot send to multiple webhooks");
-                exit(-1);
+                return Err("cannot set multiple job names".into());
             }
             continue;
         }
 
-        if x.starts_with("every ") {
-            if delay.is_some() {
-                eprintln!("{exe}: cannot assign multiple days");
-                exit(-1);
+        if x.starts_with("include ") {
+            let x = x.split_once(' ').unwrap().1.trim();
+            let (path, name) = match x.split_once(" as ") {
+                Some((path, name)) => (path.trim(), name.trim().trim_matches('/')),
+                None => {
+                    // Only drop what makes the path absolute or relative,
+                    // '.ssh' has to stay '.ssh'
+                    let mut name = x.trim_end_matches('/').trim_start_matches('/');
+                    while let Some(x) = name.strip_prefix("./") {
+                        name = x.trim_start_matches('/');
+                    }
+                    (x, name)
+                }
+            };
+            if path.is_empty() {
+                return Err(
+                    "invalid include directive, expected 'include <path> [as <archive-path>]'"
+                        .into(),
+                );
             }
-            delay.replace(Duration::ZERO);
-            let d = delay.as_mut().unwrap();
-            let mut iter = x.split(' ').skip(1);
-            while let Some(x) = iter.next() {
-                if let Ok(value) = x.parse() {
-                    let Some(unit) = iter.next() else {
-                        eprintln!("{exe}: failed to parse duration: unit is not specified");
-                        exit(-1);
-                    };
-
-                    let Some(unit) = TIME_TABLE.iter().find(|x| x.aliases.contains(&unit)) else {
-                        eprintln!("{exe}: failed to parse duration: unknown unit '{unit}'");
-                        exit(-1);
-                    };
+            if name
+                .split('/')
+                .any(|x| x.is_empty() || x == "." || x == "..")
+            {
+                return Err(format!(
+                    "invalid archive path {name:?} for {path:?}, name it with 'include <path> as <archive-path>'"
+                ));
+            }
+            let name = name.to_string();
+            include.push((PathBuf::from(path), name));
+            continue;
+        }
 
-                    *d += unit.time * value;
+        if x.starts_with("exclude ") {
+            exclude.push("", x.split_once(' ').unwrap().1);
+            continue;
+        }
 
-                    continue;
+        if x.starts_with("max-file-size ") {
+            if let Some(value) = parse_size(x.split_once(' ').unwrap().1) {
+                if max_file_size.replace(value).is_some() {
+                    return Err("cannot set multiple max file sizes".into());
                 }
-                if let Some(unit) = x.find::<fn(char) -> bool>(|x| !x.is_numeric()) {
-                    if unit != 0 {
-                        let (value, unit) = x.split_at(unit);
-                        let Some(value): Option<u32> = value.parse().ok() else {
-                            eprintln!("{exe}: failed to parse duration: invalid time");
-                            exit(-1);
-                        };
-
-                        let Some(unit) = TIME_TABLE.iter().find(|x| x.aliases.contains(&unit))
-                        else {
-                            eprintln!("{exe}: failed to parse duration: unknown unit '{unit}'");
-                            exit(-1);
-                        };
-
-                        *d += unit.time * value;
-
-                        continue;
+                continue;
+            } else {
+                return Err("invalid max file size".into());
+            }
+        }
+
+        if x.starts_with("env ") {
+            let Some((key, value)) = x.split_once(' ').unwrap().1.split_once('=') else {
+                return Err("invalid env directive, expected 'env KEY=VALUE'".into());
+            };
+            env.push((key.trim().to_string(), value.to_string()));
+            continue;
+        }
+
+        if x.starts_with("env-file ") {
+            env_files.push(PathBuf::from(x.split_once(' ').unwrap().1.trim()));
+            continue;
+        }
+
+        if let Some((hook, shell)) = x.split_once(' ').and_then(|(hook, shell)| {
+            match hook {
+                "pre" => Some(&mut pre),
+                "post-success" => Some(&mut post_success),
+                "post-failure" => Some(&mut post_failure),
+                _ => None,
+            }
+            .map(|x| (x, shell))
+        }) {
+            let Some(shell) = shell
+                .trim()
+                .strip_prefix("#!")
+                .map(|x| x.trim())
+                .and_then(|x| if x.is_empty() { None } else { Some(x) })
+            else {
+                return Err("failed to parse config: no shell specified for hook".into());
+            };
+            let shell: Vec<String> = shell.split(' ').map(|x| x.to_owned()).collect();
+
+            let mut body = String::new();
+            loop {
+                match lines.next() {
+                    Some(x) if x.trim() == "end" => break,
+                    Some(x) => writeln!(body, "{x}").expect("Failed to write to string"),
+                    None => {
+                        return Err("failed to parse config: hook is missing 'end'".into());
                     }
                 }
-                if let Some(unit) = TIME_TABLE.iter().find(|y| y.aliases.contains(&x)) {
-                    *d += unit.time;
-                    continue;
-                }
-                eprintln!("{exe}: failed to parse duration: undefined directive");
-                exit(-1);
+            }
+
+            if hook.replace(Script { shell, body }).is_some() {
+                return Err("cannot set the same hook multiple times".into());
             }
             continue;
         }
 
-        eprintln!("{exe}: failed to parse config: undefined directive");
-        exit(-1);
+        if let Some(secret) = Secret::parse(x, "webhook") {
+            if webhook.replace(secret).is_some() {
+                return Err("cannot send to multiple webhooks".into());
+            }
+            continue;
+        }
+
+        if x.starts_with("every ") {
+            let Some(every) = parse_duration(x.split_once(' ').unwrap().1).filter(|x| !x.is_zero())
+            else {
+                return Err("failed to parse every: expected a duration such as 6 hours".into());
+            };
+            if delay.replace(every).is_some() {
+                return Err("cannot assign multiple days".into());
+            }
+            continue;
+        }
+
+        return Err("failed to parse config: undefined directive".into());
     }
 
-    let shellstr = match lines
-        .next()
-        .and_then(|x| x.trim().strip_prefix("#!"))
-        .map(|x| x.trim())
-        .and_then(|x| if x.is_empty() { None } else { Some(x) })
-    {
-        Some(x) => x,
-        None => {
-            eprintln!("{exe}: failed to parse config: no shell specified");
-            exit(-1);
+    let script = match lines.next() {
+        Some(x) => {
+            let Some(shellstr) = x.trim().strip_prefix("#!").map(|x| x.trim()).and_then(|x| {
+                if x.is_empty() {
+                    None
+                } else {
+                    Some(x)
+                }
+            }) else {
+                return Err("failed to parse config: no shell specified".into());
+            };
+
+            let shell: Vec<String> = shellstr.split(' ').map(|x| x.to_owned()).collect();
+
+            let body = lines.fold(String::new(), |mut acc, x| {
+                writeln!(acc, "{x}").expect("Failed to write to string");
+                acc
+            });
+
+            Some(Script { shell, body })
         }
+        None => None,
     };
 
-    let shell: Vec<String> = shellstr.split(' ').map(|x| x.to_owned()).collect();
+    if script.is_none() && include.is_empty() {
+        return Err("failed to parse config: no script or include directives specified".into());
+    }
 
-    let script = lines.fold(String::new(), |mut acc, x| {
-        writeln!(acc, "{x}").expect("Failed to write to string");
-        acc
+    let format = format.unwrap_or(Format::Zip);
+    if encryption.is_some() && password.is_none() {
+        return Err("encryption mode is set, but no password is specified".into());
+    }
+    let encryption = encryption.unwrap_or(match format {
+        Format::Zip => Encryption::Entries,
+        _ => Encryption::Archive,
     });
+    if password.is_some() && encryption == Encryption::Archive && !recipients.is_empty() {
+        return Err("archive password encryption cannot be combined with recipients".into());
+    }
+    if encryption == Encryption::Entries && format != Format::Zip {
+        return Err("entry encryption is only supported for the zip format".into());
+    }
+    if !compress.is_empty() && format != Format::Zip {
+        return Err("compress directives are only supported for the zip format".into());
+    }
 
-    Config {
-        webhook: match webhook {
+    let http = http::Options {
+        backend: http_backend.unwrap_or_default(),
+        timeout: http_timeout.unwrap_or(http::DEFAULT_TIMEOUT),
+        proxy: proxy.unwrap_or_else(Proxy::from_env),
+        ca_file,
+        throttle: (!bandwidth_limits.is_empty()).then(|| Arc::new(Throttle::new(bandwidth_limits))),
+    };
+    let client = match http.client() {
+        Ok(x) => x,
+        Err(why) => {
+            return Err(format!("failed to set up http client: {why}"));
+        }
+    };
+
+    let healthchecks = match Healthchecks::new(healthchecks, &http) {
+        Ok(x) => x,
+        Err(why) => {
+            return Err(format!("failed to set up healthcheck http client: {why}"));
+        }
+    };
+
+    let lock_key = match &name {
+        Some(x) => format!("job-{x}"),
+        None => format!(
+            "config-{}",
+            fs::canonicalize(&config).map_or(config.clone(), |x| x.to_string_lossy().into_owned())
+        ),
+    };
+
+    let mut config = Config {
+        path: config.clone(),
+        lock_key,
+        name: name.unwrap_or_else(|| {
+            PathBuf::from(&config)
+                .file_stem()
+                .map(|x| x.to_string_lossy().into_owned())
+                .unwrap_or(config.clone())
+        }),
+        webhook: Webhook::new(String::new(), client),
+        webhook_source: match webhook {
             Some(x) => x,
             None => {
-                eprintln!("{exe}: failed to parse config: missing webhook directive");
-                exit(-1);
+                return Err("failed to parse config: missing webhook directive".into());
             }
         },
         delay: match delay {
             Some(x) => x,
             None => {
-                eprintln!("{exe}: failed to parse config: missing every directive");
-                exit(-1);
+                return Err("failed to parse config: missing every directive".into());
             }
         },
+        format,
         compression_level: compression.unwrap_or(10),
+        compress,
         block_size: block_size.unwrap_or(10),
-        shell,
+        script_log_size: script_log_size.unwrap_or(64 * 1024),
+        archive_script_log,
+        env,
+        env_files,
+        temp_dir,
+        state_file,
+        lock: lock.unwrap_or(LockMode::Fail),
+        control_socket,
+        metrics_file,
+        metrics_listen,
+        healthchecks,
+        log: log::Options {
+            level: log_level.unwrap_or(Level::Info),
+            format: log_format.unwrap_or(log::Format::Text),
+            color: log_color.unwrap_or(Color::Auto),
+            file: log_file,
+            max_size: log_max_size.unwrap_or(log::DEFAULT_MAX_SIZE),
+            keep: log_keep.unwrap_or(log::DEFAULT_KEEP),
+        }
+        .with_overrides(),
+        http,
         script,
-        password,
+        include,
+        exclude,
+        max_file_size,
+        pre,
+        post_success,
+        post_failure,
+        password: None,
+        password_source: password,
+        encryption,
+        recipients,
+        signing_key: None,
+        signing_key_source: signing_key,
+        verify_key,
+    };
+
+    let resolved = match mode {
+        Mode::Backup | Mode::Once => config.resolve_secrets(),
+        Mode::Restore { .. } => config.resolve_password(),
+        Mode::Control(_) => Ok(()),
+    };
+    resolved?;
+
+    Ok(config)
+}
+
+/// Read `KEY=VALUE` pairs from an env file.
+///
+/// Empty lines and lines starting with `#` are ignored, values may be quoted
+/// and lines may start with `export`.
+pub fn read_env_file(path: &PathBuf) -> Result<Vec<(String, String)>, String> {
+    let file = fs::read_to_string(path).map_err(|why| format!("{path:?}: {why}"))?;
+    let mut env = vec![];
+
+    for (i, x) in file.lines().enumerate() {
+        let x = x.trim();
+
+        if x.starts_with('#') || x.is_empty() {
+            continue;
+        }
+
+        let x = x.strip_prefix("export ").unwrap_or(x);
+        let Some((key, value)) = x.split_once('=') else {
+            return Err(format!("{path:?}:{}: expected KEY=VALUE", i + 1));
+        };
+        let value = value.trim();
+        let value = ['"', '\'']
+            .into_iter()
+            .find_map(|q| value.strip_prefix(q).and_then(|x| x.strip_suffix(q)))
+            .unwrap_or(value);
+
+        env.push((key.trim().to_string(), value.to_string()));
+    }
+
+    Ok(env)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::parse_duration;
+
+    #[test]
+    fn durations() {
+        let secs = |x| Some(Duration::from_secs(x));
+        assert_eq!(parse_duration("30s"), secs(30));
+        assert_eq!(parse_duration("2 min"), secs(120));
+        assert_eq!(parse_duration("1m 30s"), secs(90));
+        assert_eq!(parse_duration("6 hours"), secs(6 * 60 * 60));
+        assert_eq!(parse_duration("day"), secs(24 * 60 * 60));
+        assert_eq!(parse_duration("1 week 2d"), secs(9 * 24 * 60 * 60));
+        assert_eq!(parse_duration("250ms"), Some(Duration::from_millis(250)));
+        assert_eq!(parse_duration(""), Some(Duration::ZERO));
+    }
+
+    #[test]
+    fn invalid_durations() {
+        assert_eq!(parse_duration("5"), None);
+        assert_eq!(parse_duration("5 fortnights"), None);
+        assert_eq!(parse_duration("-5s"), None);
+        assert_eq!(parse_duration("1.5h"), None);
+        assert_eq!(parse_duration("4294967296s"), None);
     }
 }
@@ -0,0 +1,368 @@
+//! Runtime control of the daemon over a Unix socket, used by
+//! `discord-backup-util ctl`.
+//!
+//! Clients send a single JSON object per connection, e.g.
+//! `{"command":"trigger","job":"db"}`, and get a single JSON object back
+//! with `"ok"` telling whether the command succeeded.
+// The socket only exists on unix
+#![cfg_attr(windows, allow(dead_code))]
+
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use tinyjson::JsonValue;
+
+use crate::{signal, time};
+
+/// A `ctl` subcommand.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Command {
+    /// Show the current phase, progress, last result and next run.
+    Status,
+    /// Start a backup of the job now.
+    Trigger(String),
+    /// Stop the running backup, the daemon keeps running.
+    Cancel,
+    /// Show when the next backup starts.
+    Next,
+}
+
+struct Outcome {
+    success: bool,
+    status: String,
+    finished_at: SystemTime,
+}
+
+struct State {
+    job: String,
+    /// Head message status of the running backup.
+    phase: Option<String>,
+    /// Chunks uploaded and total chunks of the running backup.
+    chunks: Option<(u64, u64)>,
+    last: Option<Outcome>,
+    next: Option<SystemTime>,
+}
+
+static STATE: Mutex<State> = Mutex::new(State {
+    job: String::new(),
+    phase: None,
+    chunks: None,
+    last: None,
+    next: None,
+});
+
+pub fn set_job(name: &str) {
+    STATE.lock().unwrap().job = name.to_string();
+}
+
+pub fn started() {
+    let mut state = STATE.lock().unwrap();
+    state.phase = Some("Starting backup process...".into());
+    state.chunks = None;
+}
+
+pub fn phase(text: &str) {
+    STATE.lock().unwrap().phase = Some(text.lines().next().unwrap_or_default().to_string());
+}
+
+pub fn chunks(done: u64, total: u64) {
+    STATE.lock().unwrap().chunks = Some((done, total));
+}
+
+pub fn finished(success: bool, status: &str) {
+    let mut state = STATE.lock().unwrap();
+    state.phase = None;
+    state.chunks = None;
+    state.last = Some(Outcome {
+        success,
+        status: status.to_string(),
+        finished_at: SystemTime::now(),
+    });
+}
+
+/// Record when the next backup starts.
+pub fn scheduled(next: Instant) {
+    STATE.lock().unwrap().next =
+        Some(SystemTime::now() + next.saturating_duration_since(Instant::now()));
+}
+
+fn unix(time: SystemTime) -> JsonValue {
+    JsonValue::Number(
+        time.duration_since(UNIX_EPOCH)
+            .map(|x| x.as_secs() as f64)
+            .unwrap_or_default(),
+    )
+}
+
+fn object<const N: usize>(fields: [(&str, JsonValue); N]) -> JsonValue {
+    JsonValue::Object(
+        fields
+            .into_iter()
+            .map(|(k, v)| (k.to_string(), v))
+            .collect(),
+    )
+}
+
+fn error(message: impl Into<String>) -> JsonValue {
+    object([
+        ("ok", JsonValue::Boolean(false)),
+        ("error", JsonValue::String(message.into())),
+    ])
+}
+
+/// Answer a request from a client.
+fn handle(request: &str) -> JsonValue {
+    let Ok(JsonValue::Object(request)) = request.parse::<JsonValue>() else {
+        return error("malformed request");
+    };
+    let field = |name: &str| match request.get(name) {
+        Some(JsonValue::String(x)) => Some(x.as_str()),
+        _ => None,
+    };
+
+    let state = STATE.lock().unwrap();
+    let next = || state.next.map_or(JsonValue::Null, unix);
+    match field("command") {
+        Some("status") => object([
+            ("ok", JsonValue::Boolean(true)),
+            ("job", JsonValue::String(state.job.clone())),
+            ("running", JsonValue::Boolean(state.phase.is_some())),
+            (
+                "phase",
+                state
+                    .phase
+                    .clone()
+                    .map_or(JsonValue::Null, JsonValue::String),
+            ),
+            (
+                "progress",
+                state.chunks.map_or(JsonValue::Null, |(done, total)| {
+                    object([
+                        ("chunks_done", JsonValue::Number(done as f64)),
+                        ("chunks_total", JsonValue::Number(total as f64)),
+                    ])
+                }),
+            ),
+            (
+                "last_result",
+                state.last.as_ref().map_or(JsonValue::Null, |x| {
+                    object([
+                        ("success", JsonValue::Boolean(x.success)),
+                        ("status", JsonValue::String(x.status.clone())),
+                        ("finished_at", unix(x.finished_at)),
+                    ])
+                }),
+            ),
+            ("next_run", next()),
+        ]),
+        Some("trigger") => {
+            if field("job") != Some(state.job.as_str()) {
+                return error(format!(
+                    "unknown job {:?}, this daemon runs {:?}",
+                    field("job").unwrap_or_default(),
+                    state.job
+                ));
+            }
+            if state.phase.is_some() {
+                return error("a backup is already running");
+            }
+            signal::trigger();
+            object([("ok", JsonValue::Boolean(true))])
+        }
+        Some("cancel") => {
+            if state.phase.is_none() {
+                return error("no backup is running");
+            }
+            signal::cancel();
+            object([("ok", JsonValue::Boolean(true))])
+        }
+        Some("next") => object([("ok", JsonValue::Boolean(true)), ("next_run", next())]),
+        _ => error("unknown command"),
+    }
+}
+
+/// Listening socket, removed when dropped.
+#[cfg(unix)]
+pub struct Server {
+    path: std::path::PathBuf,
+}
+#[cfg(unix)]
+impl Drop for Server {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+/// Start answering requests on `path` in the background.
+#[cfg(unix)]
+pub fn serve(path: &std::path::Path) -> std::io::Result<Server> {
+    use std::{
+        fs,
+        io::{BufRead, BufReader, Write},
+        os::unix::net::{UnixListener, UnixStream},
+    };
+
+    // Leftover of an instance that didn't shut down cleanly
+    if path.exists() {
+        if UnixStream::connect(path).is_ok() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::AddrInUse,
+                "another instance is listening",
+            ));
+        }
+        fs::remove_file(path)?;
+    }
+
+    // Create it only we can use right away, changing the permissions after
+    // binding leaves a window for anyone to connect
+    let mask = unsafe { libc::umask(0o177) };
+    let listener = UnixListener::bind(path);
+    unsafe { libc::umask(mask) };
+    let listener = listener?;
+
+    std::thread::spawn(move || {
+        for stream in listener.incoming() {
+            let Ok(mut stream) = stream else {
+                continue;
+            };
+            // Don't let a stuck client block everyone else
+            let _ = stream.set_read_timeout(Some(Duration::from_secs(5)));
+            let _ = stream.set_write_timeout(Some(Duration::from_secs(5)));
+
+            let mut request = String::new();
+            if BufReader::new(&stream).read_line(&mut request).is_err() {
+                continue;
+            }
+            let response = handle(&request).stringify().unwrap_or_default();
+            let _ = writeln!(stream, "{response}");
+        }
+    });
+
+    Ok(Server {
+        path: path.to_path_buf(),
+    })
+}
+
+/// Send `command` to the daemon listening on `path`.
+#[cfg(unix)]
+fn request(
+    path: &std::path::Path,
+    command: &Command,
+) -> Result<HashMap<String, JsonValue>, String> {
+    use std::{
+        io::{BufRead, BufReader, Write},
+        os::unix::net::UnixStream,
+    };
+
+    let request = match command {
+        Command::Status => object([("command", JsonValue::String("status".into()))]),
+        Command::Trigger(job) => object([
+            ("command", JsonValue::String("trigger".into())),
+            ("job", JsonValue::String(job.clone())),
+        ]),
+        Command::Cancel => object([("command", JsonValue::String("cancel".into()))]),
+        Command::Next => object([("command", JsonValue::String("next".into()))]),
+    };
+
+    let mut stream = UnixStream::connect(path)
+        .map_err(|why| format!("failed to connect to {path:?}, is the daemon running? {why}"))?;
+    let _ = stream.set_read_timeout(Some(Duration::from_secs(10)));
+    writeln!(stream, "{}", request.stringify().unwrap_or_default())
+        .map_err(|why| format!("failed to send request: {why}"))?;
+
+    let mut response = String::new();
+    BufReader::new(&stream)
+        .read_line(&mut response)
+        .map_err(|why| format!("failed to read response: {why}"))?;
+
+    let Ok(JsonValue::Object(response)) = response.parse::<JsonValue>() else {
+        return Err("malformed response".into());
+    };
+    match (response.get("ok"), response.get("error")) {
+        (Some(JsonValue::Boolean(true)), _) => Ok(response),
+        (_, Some(JsonValue::String(why))) => Err(why.clone()),
+        _ => Err("request failed".into()),
+    }
+}
+
+#[cfg(windows)]
+fn request(_: &std::path::Path, _: &Command) -> Result<HashMap<String, JsonValue>, String> {
+    Err("the control socket is not supported on Windows".into())
+}
+
+/// Local time at a unix timestamp, with how far it is from now.
+fn format_time(value: Option<&JsonValue>) -> String {
+    let Some(JsonValue::Number(time)) = value else {
+        return "unknown".into();
+    };
+    let time = *time as i64;
+    let (year, month, day, hour, minute, second) = time::civil(time + time::local_offset(time));
+
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|x| x.as_secs() as i64)
+        .unwrap_or_default();
+    let delta = (time - now).unsigned_abs();
+    let delta = if delta >= 3600 {
+        format!("{}h {}m", delta / 3600, delta / 60 % 60)
+    } else if delta >= 60 {
+        format!("{}m {}s", delta / 60, delta % 60)
+    } else {
+        format!("{delta}s")
+    };
+    let relative = if time >= now {
+        format!("in {delta}")
+    } else {
+        format!("{delta} ago")
+    };
+
+    format!("{year}-{month:02}-{day:02} {hour:02}:{minute:02}:{second:02} ({relative})")
+}
+
+/// Run `command` against the daemon listening on `path`, printing the result.
+pub fn run(path: &std::path::Path, command: &Command) -> Result<(), String> {
+    let response = request(path, command)?;
+    let string = |x: Option<&JsonValue>| match x {
+        Some(JsonValue::String(x)) => Some(x.clone()),
+        _ => None,
+    };
+
+    match command {
+        Command::Status => {
+            println!("job: {}", string(response.get("job")).unwrap_or_default());
+            match string(response.get("phase")) {
+                Some(phase) => println!("phase: {phase}"),
+                None => println!("phase: idle"),
+            }
+            if let Some(JsonValue::Object(progress)) = response.get("progress") {
+                if let (Some(JsonValue::Number(done)), Some(JsonValue::Number(total))) =
+                    (progress.get("chunks_done"), progress.get("chunks_total"))
+                {
+                    println!("progress: {done}/{total} chunks uploaded");
+                }
+            }
+            match response.get("last_result") {
+                Some(JsonValue::Object(last)) => println!(
+                    "last run: {} at {}: {}",
+                    if matches!(last.get("success"), Some(JsonValue::Boolean(true))) {
+                        "succeeded"
+                    } else {
+                        "failed"
+                    },
+                    format_time(last.get("finished_at")),
+                    string(last.get("status")).unwrap_or_default(),
+                ),
+                _ => println!("last run: none"),
+            }
+            println!("next run: {}", format_time(response.get("next_run")));
+        }
+        Command::Trigger(job) => println!("Started a backup of {job:?}"),
+        Command::Cancel => println!("Cancelling the running backup"),
+        Command::Next => println!("{}", format_time(response.get("next_run"))),
+    }
+
+    Ok(())
+}
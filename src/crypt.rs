@@ -0,0 +1,192 @@
+//! Password encryption of whole archives.
+//!
+//! An encrypted archive starts with [MAGIC], the Argon2id memory, iteration
+//! and parallelism costs as little endian `u32`s, a 16 byte salt and a 19 byte
+//! nonce. The rest is XChaCha20-Poly1305 in the STREAM construction, every
+//! segment holding 64KiB of the archive, the last one possibly less.
+
+use std::io::{self, Read, Write};
+
+use argon2::{Algorithm, Argon2, Params, Version};
+use chacha20poly1305::{
+    aead::stream::{DecryptorBE32, EncryptorBE32},
+    KeyInit, XChaCha20Poly1305,
+};
+use rand::RngCore;
+
+pub const MAGIC: &[u8; 8] = b"DBUCRYPT";
+
+const SEGMENT: usize = 64 * 1024;
+const TAG: usize = 16;
+const SALT: usize = 16;
+const NONCE: usize = 19;
+
+/// Highest Argon2 costs accepted from an archive header, way above what
+/// [Encryptor] writes but low enough that a crafted archive can't make a
+/// restore take all memory or hours.
+const MAX_M_COST: u32 = 1024 * 1024;
+const MAX_T_COST: u32 = 16;
+const MAX_P_COST: u32 = 16;
+
+/// What the password protects.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Encryption {
+    /// Contents of zip entries, names and sizes stay visible.
+    Entries,
+    /// The whole archive.
+    Archive,
+}
+impl Encryption {
+    pub fn parse(value: &str) -> Option<Self> {
+        match value {
+            "entries" => Some(Self::Entries),
+            "archive" => Some(Self::Archive),
+            _ => None,
+        }
+    }
+}
+
+fn cipher(password: &str, params: Params, salt: &[u8]) -> io::Result<XChaCha20Poly1305> {
+    let mut key = [0u8; 32];
+    Argon2::new(Algorithm::Argon2id, Version::V0x13, params)
+        .hash_password_into(password.as_bytes(), salt, &mut key)
+        .map_err(|why| io::Error::other(format!("failed to derive key: {why}")))?;
+    Ok(XChaCha20Poly1305::new(&key.into()))
+}
+
+/// Read `len` bytes or less if the reader ends first.
+fn read_up_to(reader: &mut impl Read, buffer: &mut Vec<u8>, len: usize) -> io::Result<()> {
+    let len = len.saturating_sub(buffer.len());
+    reader.take(len as u64).read_to_end(buffer)?;
+    Ok(())
+}
+
+/// Size of `len` bytes once encrypted with [Encryptor].
+pub fn encrypted_len(len: u64) -> u64 {
+    let header = MAGIC.len() + 3 * 4 + SALT + NONCE;
+    let segments = len.div_ceil(SEGMENT as u64).max(1);
+    header as u64 + len + segments * TAG as u64
+}
+
+/// Reader encrypting everything read from the inner reader.
+pub struct Encryptor<R> {
+    reader: R,
+    stream: Option<EncryptorBE32<XChaCha20Poly1305>>,
+    /// Plaintext read ahead, one byte more than a segment tells whether
+    /// the segment is the last one.
+    input: Vec<u8>,
+    output: Vec<u8>,
+    position: usize,
+}
+impl<R: Read> Encryptor<R> {
+    pub fn new(reader: R, password: &str) -> io::Result<Self> {
+        let mut salt = [0u8; SALT];
+        let mut nonce = [0u8; NONCE];
+        rand::thread_rng().fill_bytes(&mut salt);
+        rand::thread_rng().fill_bytes(&mut nonce);
+
+        let params = Params::default();
+        let cipher = cipher(password, params.clone(), &salt)?;
+
+        let mut output = MAGIC.to_vec();
+        output.extend(params.m_cost().to_le_bytes());
+        output.extend(params.t_cost().to_le_bytes());
+        output.extend(params.p_cost().to_le_bytes());
+        output.extend(salt);
+        output.extend(nonce);
+
+        Ok(Self {
+            reader,
+            stream: Some(EncryptorBE32::from_aead(cipher, &nonce.into())),
+            input: Vec::with_capacity(SEGMENT + 1),
+            output,
+            position: 0,
+        })
+    }
+}
+impl<R: Read> Read for Encryptor<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.position == self.output.len() {
+            if self.stream.is_none() {
+                return Ok(0);
+            }
+
+            read_up_to(&mut self.reader, &mut self.input, SEGMENT + 1)?;
+
+            let error = |_| io::Error::other("failed to encrypt archive");
+            self.output = if self.input.len() > SEGMENT {
+                let next = self.input.split_off(SEGMENT);
+                let segment = std::mem::replace(&mut self.input, next);
+                self.stream
+                    .as_mut()
+                    .unwrap()
+                    .encrypt_next(segment.as_slice())
+                    .map_err(error)?
+            } else {
+                self.stream
+                    .take()
+                    .unwrap()
+                    .encrypt_last(self.input.as_slice())
+                    .map_err(error)?
+            };
+            self.position = 0;
+        }
+
+        let len = buf.len().min(self.output.len() - self.position);
+        buf[..len].copy_from_slice(&self.output[self.position..self.position + len]);
+        self.position += len;
+        Ok(len)
+    }
+}
+
+/// Decrypt an archive created with [Encryptor].
+pub fn decrypt(
+    mut reader: impl Read,
+    mut writer: impl Write,
+    password: &str,
+) -> Result<(), String> {
+    let error = |why: io::Error| format!("Failed to decrypt archive: {why}");
+
+    let mut header = vec![];
+    read_up_to(&mut reader, &mut header, MAGIC.len() + 12 + SALT + NONCE).map_err(error)?;
+    if header.len() < MAGIC.len() + 12 + SALT + NONCE || !header.starts_with(MAGIC) {
+        return Err("Failed to decrypt archive: not an encrypted archive".into());
+    }
+
+    let cost = |i: usize| {
+        let offset = MAGIC.len() + i * 4;
+        u32::from_le_bytes(header[offset..offset + 4].try_into().unwrap())
+    };
+    let (m_cost, t_cost, p_cost) = (cost(0), cost(1), cost(2));
+    if m_cost > MAX_M_COST || t_cost > MAX_T_COST || p_cost > MAX_P_COST {
+        return Err(format!(
+            "Failed to decrypt archive: Argon2 costs of {m_cost} KiB, {t_cost} passes and {p_cost} lanes exceed the limits of {MAX_M_COST} KiB, {MAX_T_COST} passes and {MAX_P_COST} lanes"
+        ));
+    }
+    let params = Params::new(m_cost, t_cost, p_cost, None)
+        .map_err(|why| format!("Failed to decrypt archive: {why}"))?;
+    let salt = &header[MAGIC.len() + 12..MAGIC.len() + 12 + SALT];
+    let nonce: [u8; NONCE] = header[MAGIC.len() + 12 + SALT..].try_into().unwrap();
+
+    let cipher = cipher(password, params, salt).map_err(error)?;
+    let mut stream = DecryptorBE32::from_aead(cipher, &nonce.into());
+
+    let wrong = || "Failed to decrypt archive: wrong password or corrupted archive".to_string();
+    let mut input = Vec::with_capacity(SEGMENT + TAG + 1);
+    loop {
+        read_up_to(&mut reader, &mut input, SEGMENT + TAG + 1).map_err(error)?;
+
+        if input.len() > SEGMENT + TAG {
+            let next = input.split_off(SEGMENT + TAG);
+            let segment = std::mem::replace(&mut input, next);
+            let data = stream
+                .decrypt_next(segment.as_slice())
+                .map_err(|_| wrong())?;
+            writer.write_all(&data).map_err(error)?;
+        } else {
+            let data = stream.decrypt_last(input.as_slice()).map_err(|_| wrong())?;
+            writer.write_all(&data).map_err(error)?;
+            return writer.flush().map_err(error);
+        }
+    }
+}
@@ -0,0 +1,343 @@
+#[derive(Debug, Clone)]
+enum Token {
+    Char(char),
+    /// `?`
+    Any,
+    /// `*`, does not match `/`.
+    Star,
+    /// `**`, matches anything including `/`.
+    Recursive,
+    /// `**/`, matches nothing or anything ending with `/`.
+    RecursiveDir,
+    /// `[...]`
+    Class(Vec<(char, char)>, bool),
+}
+
+/// A shell-style glob pattern.
+///
+/// Patterns without a `/` are matched against the file name, others
+/// against the whole path.
+#[derive(Debug, Clone)]
+pub struct Glob {
+    tokens: Vec<Token>,
+    anchored: bool,
+}
+impl Glob {
+    pub fn new(pattern: &str) -> Self {
+        let pattern = pattern.trim_end_matches('/');
+        let anchored = pattern.contains('/');
+        let pattern = pattern.trim_start_matches('/');
+
+        let mut tokens = vec![];
+        let mut chars = pattern.chars().peekable();
+
+        while let Some(x) = chars.next() {
+            tokens.push(match x {
+                '?' => Token::Any,
+                '*' if chars.peek() == Some(&'*') => {
+                    chars.next();
+                    if chars.peek() == Some(&'/') {
+                        chars.next();
+                        Token::RecursiveDir
+                    } else {
+                        Token::Recursive
+                    }
+                }
+                '*' => Token::Star,
+                '[' => {
+                    let mut class = vec![];
+                    let negated = matches!(chars.peek(), Some('!' | '^'));
+                    if negated {
+                        chars.next();
+                    }
+                    let mut closed = false;
+                    while let Some(x) = chars.next() {
+                        if x == ']' && !class.is_empty() {
+                            closed = true;
+                            break;
+                        }
+                        let x = if x == '\\' {
+                            chars.next().unwrap_or(x)
+                        } else {
+                            x
+                        };
+                        if chars.peek() == Some(&'-') {
+                            chars.next();
+                            match chars.next() {
+                                Some(']') => {
+                                    class.push((x, x));
+                                    class.push(('-', '-'));
+                                    closed = true;
+                                    break;
+                                }
+                                Some(y) => class.push((x, y)),
+                                None => class.push((x, x)),
+                            }
+                        } else {
+                            class.push((x, x));
+                        }
+                    }
+                    if !closed {
+                        // Unterminated class, treat it literally
+                        tokens.push(Token::Char('['));
+                        class
+                            .into_iter()
+                            .for_each(|(x, _)| tokens.push(Token::Char(x)));
+                        continue;
+                    }
+                    Token::Class(class, negated)
+                }
+                '\\' => Token::Char(chars.next().unwrap_or('\\')),
+                x => Token::Char(x),
+            });
+        }
+
+        Self { tokens, anchored }
+    }
+
+    /// Match a `/`-separated path relative to the pattern root.
+    pub fn matches(&self, path: &str) -> bool {
+        let path = path.trim_matches('/');
+        let path = if self.anchored {
+            path
+        } else {
+            path.rsplit('/').next().unwrap_or(path)
+        };
+        let path: Vec<char> = path.chars().collect();
+        matches(&self.tokens, &path)
+    }
+}
+
+/// Whether `tokens` match all of `path`.
+fn matches(tokens: &[Token], path: &[char]) -> bool {
+    // Remembers how each (token, char) pair went, so patterns with many stars
+    // don't backtrack exponentially
+    let mut memo = vec![None; (tokens.len() + 1) * (path.len() + 1)];
+    matches_from(tokens, path, 0, 0, &mut memo)
+}
+
+/// Whether `tokens[t..]` match `path[p..]`.
+fn matches_from(
+    tokens: &[Token],
+    path: &[char],
+    t: usize,
+    p: usize,
+    memo: &mut [Option<bool>],
+) -> bool {
+    let key = t * (path.len() + 1) + p;
+    if let Some(x) = memo[key] {
+        return x;
+    }
+
+    let result = match &tokens.get(t) {
+        None => p == path.len(),
+        Some(Token::Char(x)) => {
+            path.get(p) == Some(x) && matches_from(tokens, path, t + 1, p + 1, memo)
+        }
+        Some(Token::Any) => {
+            path.get(p).is_some_and(|x| *x != '/') && matches_from(tokens, path, t + 1, p + 1, memo)
+        }
+        Some(Token::Class(class, negated)) => {
+            path.get(p).is_some_and(|x| {
+                *x != '/' && class.iter().any(|(a, b)| (a..=b).contains(&x)) != *negated
+            }) && matches_from(tokens, path, t + 1, p + 1, memo)
+        }
+        Some(Token::Star) => {
+            let mut found = false;
+            for i in p..=path.len() {
+                if matches_from(tokens, path, t + 1, i, memo) {
+                    found = true;
+                    break;
+                }
+                if path.get(i) == Some(&'/') {
+                    break;
+                }
+            }
+            found
+        }
+        Some(Token::Recursive) => {
+            (p..=path.len()).any(|i| matches_from(tokens, path, t + 1, i, memo))
+        }
+        Some(Token::RecursiveDir) => {
+            matches_from(tokens, path, t + 1, p, memo)
+                || (p + 1..=path.len())
+                    .any(|i| path[i - 1] == '/' && matches_from(tokens, path, t + 1, i, memo))
+        }
+    };
+
+    memo[key] = Some(result);
+    result
+}
+
+#[derive(Debug, Clone)]
+struct Rule {
+    /// Archive path of the directory the rule was defined in.
+    base: String,
+    glob: Glob,
+    negated: bool,
+    dir_only: bool,
+}
+
+/// A gitignore-style list of exclude rules.
+///
+/// Later rules take precedence, rules starting with `!` include back
+/// previously excluded paths and rules ending with `/` only match
+/// directories.
+#[derive(Debug, Clone, Default)]
+pub struct Ignore(Vec<Rule>);
+impl Ignore {
+    /// Add a rule relative to the `base` archive path.
+    pub fn push(&mut self, base: &str, pattern: &str) {
+        let pattern = pattern.trim();
+        if pattern.is_empty() || pattern.starts_with('#') {
+            return;
+        }
+
+        let (negated, pattern) = match pattern.strip_prefix('!') {
+            Some(x) => (true, x),
+            None => (false, pattern.strip_prefix('\\').unwrap_or(pattern)),
+        };
+
+        self.0.push(Rule {
+            base: base.trim_matches('/').to_string(),
+            glob: Glob::new(pattern),
+            negated,
+            dir_only: pattern.ends_with('/'),
+        });
+    }
+
+    /// Add all rules from an ignore file's contents.
+    pub fn extend(&mut self, base: &str, file: &str) {
+        file.lines().for_each(|x| self.push(base, x));
+    }
+
+    pub fn is_ignored(&self, path: &str, is_dir: bool) -> bool {
+        let path = path.trim_matches('/');
+        let mut ignored = false;
+
+        for x in &self.0 {
+            if x.dir_only && !is_dir {
+                continue;
+            }
+
+            let path = if x.base.is_empty() {
+                path
+            } else {
+                match path.strip_prefix(&x.base).and_then(|x| x.strip_prefix('/')) {
+                    Some(x) => x,
+                    None => continue,
+                }
+            };
+
+            if x.glob.matches(path) {
+                ignored = !x.negated;
+            }
+        }
+
+        ignored
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ignore(rules: &[&str]) -> Ignore {
+        let mut ignore = Ignore::default();
+        rules.iter().for_each(|x| ignore.push("", x));
+        ignore
+    }
+
+    #[test]
+    fn patterns_without_slash_match_names_anywhere() {
+        let glob = Glob::new("*.log");
+        assert!(glob.matches("a.log"));
+        assert!(glob.matches("var/log/a.log"));
+        assert!(!glob.matches("a.log/b"));
+        assert!(!glob.matches("a.logs"));
+    }
+
+    #[test]
+    fn patterns_with_slash_are_anchored() {
+        let glob = Glob::new("/build");
+        assert!(glob.matches("build"));
+        assert!(!glob.matches("src/build"));
+
+        let glob = Glob::new("src/*.rs");
+        assert!(glob.matches("src/main.rs"));
+        assert!(!glob.matches("lib/src/main.rs"));
+        assert!(!glob.matches("src/nested/main.rs"));
+    }
+
+    #[test]
+    fn double_stars() {
+        let glob = Glob::new("**/cache");
+        assert!(glob.matches("cache"));
+        assert!(glob.matches("a/b/cache"));
+        assert!(!glob.matches("a/b/cache2"));
+
+        let glob = Glob::new("logs/**");
+        assert!(glob.matches("logs/a"));
+        assert!(glob.matches("logs/a/b.txt"));
+        assert!(!glob.matches("other/logs/a"));
+
+        let glob = Glob::new("a/**/b");
+        assert!(glob.matches("a/b"));
+        assert!(glob.matches("a/x/y/b"));
+        assert!(!glob.matches("a/xb"));
+    }
+
+    #[test]
+    fn character_classes() {
+        let glob = Glob::new("file[0-9].[!t]xt");
+        assert!(glob.matches("file1.bxt"));
+        assert!(!glob.matches("file1.txt"));
+        assert!(!glob.matches("filex.bxt"));
+
+        assert!(Glob::new("[a-]").matches("-"));
+        assert!(Glob::new("[]]").matches("]"));
+        // Unterminated classes are literal
+        assert!(Glob::new("[ab").matches("[ab"));
+        // Never matches the separator
+        assert!(!Glob::new("a[!x]b").matches("a/b"));
+        assert!(!Glob::new("a?b").matches("a/b"));
+    }
+
+    #[test]
+    fn trailing_slash_only_matches_dirs() {
+        let ignore = ignore(&["tmp/"]);
+        assert!(ignore.is_ignored("tmp", true));
+        assert!(ignore.is_ignored("a/tmp", true));
+        assert!(!ignore.is_ignored("tmp", false));
+    }
+
+    #[test]
+    fn negation_and_precedence() {
+        let ignore = ignore(&["*.log", "!keep.log", "\\!literal"]);
+        assert!(ignore.is_ignored("a.log", false));
+        assert!(!ignore.is_ignored("keep.log", false));
+        assert!(ignore.is_ignored("!literal", false));
+
+        let ignore = super::tests::ignore(&["!keep.log", "*.log"]);
+        assert!(ignore.is_ignored("keep.log", false));
+    }
+
+    #[test]
+    fn rules_are_relative_to_their_base() {
+        let mut ignore = Ignore::default();
+        ignore.extend("data/sub", "# comment\n\n/cache\n*.tmp\n");
+        assert!(ignore.is_ignored("data/sub/cache", true));
+        assert!(!ignore.is_ignored("data/sub/x/cache", true));
+        assert!(!ignore.is_ignored("data/cache", true));
+        assert!(ignore.is_ignored("data/sub/x/a.tmp", false));
+        assert!(!ignore.is_ignored("data/a.tmp", false));
+    }
+
+    #[test]
+    fn many_stars_do_not_backtrack_exponentially() {
+        let name = "a".repeat(200);
+        assert!(!Glob::new("a*a*a*a*a*a*a*a*a*a*b").matches(&name));
+        assert!(!Glob::new("**a**a**a**a**a**a**a**a**b").matches(&name));
+        assert!(Glob::new("a*a*a*a*a*a*a*a*a*a*a").matches(&name));
+    }
+}
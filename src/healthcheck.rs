@@ -0,0 +1,128 @@
+//! Pings to dead man's switch monitors like healthchecks.io or Uptime Kuma
+//! push monitors, so someone notices when backups stop happening.
+
+use std::{sync::Arc, time::Duration};
+
+use crate::{
+    http::{self, HttpClient, Method, Options, Request},
+    log::{Level, Logger},
+};
+
+/// Pings have to be quick, they hold up the backup.
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Event {
+    Start,
+    Success,
+    Failure,
+}
+impl Event {
+    pub fn parse(value: &str) -> Option<Self> {
+        match value {
+            "start" => Some(Self::Start),
+            "success" => Some(Self::Success),
+            "failure" => Some(Self::Failure),
+            _ => None,
+        }
+    }
+
+    fn as_str(self) -> &'static str {
+        match self {
+            Self::Start => "start",
+            Self::Success => "success",
+            Self::Failure => "failure",
+        }
+    }
+}
+
+/// A URL to ping, either for one event or healthchecks.io style for all of
+/// them, with `/start` and `/fail` appended.
+#[derive(Clone)]
+pub struct Check {
+    pub url: String,
+    pub event: Option<Event>,
+}
+impl Check {
+    fn url(&self, event: Event) -> Option<String> {
+        match self.event {
+            Some(x) => (x == event).then(|| self.url.clone()),
+            None => {
+                let url = self.url.trim_end_matches('/');
+                Some(match event {
+                    Event::Start => format!("{url}/start"),
+                    Event::Success => url.to_string(),
+                    Event::Failure => format!("{url}/fail"),
+                })
+            }
+        }
+    }
+}
+
+pub struct Healthchecks {
+    checks: Vec<Check>,
+    client: Option<Arc<dyn HttpClient>>,
+}
+impl std::fmt::Debug for Healthchecks {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        // The URLs are secrets
+        f.debug_tuple("Healthchecks")
+            .field(&self.checks.len())
+            .finish()
+    }
+}
+impl Healthchecks {
+    pub fn new(checks: Vec<Check>, options: &Options) -> Result<Self, String> {
+        let client = if checks.is_empty() {
+            None
+        } else {
+            let options = Options {
+                timeout: TIMEOUT,
+                throttle: None,
+                ..options.clone()
+            };
+            Some(options.client()?)
+        };
+        Ok(Self { checks, client })
+    }
+
+    /// Tell the monitors about `event`, with `body` shown alongside it.
+    /// Failing to is logged, but never fails the backup.
+    pub fn ping<L: Logger>(&self, event: Event, body: &str, log: &mut L) {
+        let Some(client) = &self.client else {
+            return;
+        };
+
+        for url in self.checks.iter().filter_map(|x| x.url(event)) {
+            // The URL is the secret, only show where it goes
+            let host = url.split('/').take(3).collect::<Vec<_>>().join("/");
+            let request = Request::new(Method::Post, &url)
+                .body("text/plain; charset=utf-8", body.as_bytes().to_vec());
+
+            let mut log = Hidden {
+                inner: &mut *log,
+                secret: &url,
+                shown: &format!("{host}/***"),
+            };
+            if let Err(why) = http::execute(&**client, &request, Some(3), &mut log) {
+                log.warn(&format!(
+                    "Failed to send the {} ping to {host}: {why}",
+                    event.as_str()
+                ));
+            }
+        }
+    }
+}
+
+/// Hides a ping URL in everything logged through it.
+struct Hidden<'a, L: Logger> {
+    inner: &'a mut L,
+    secret: &'a str,
+    shown: &'a str,
+}
+impl<L: Logger> Logger for Hidden<'_, L> {
+    fn log(&mut self, level: Level, value: &str) {
+        self.inner
+            .log(level, &value.replace(self.secret, self.shown));
+    }
+}
@@ -1,9 +1,13 @@
-use std::{num::NonZeroU64, ops::Add, time::Duration};
+use std::{num::NonZeroU64, ops::Add, sync::Arc, time::Duration};
 
 use rand::Rng;
 use tinyjson::JsonValue;
 
-use crate::log::Logger;
+use crate::{
+    http::{self, HttpClient, Method, Request},
+    log::Logger,
+    signal,
+};
 
 #[derive(Default)]
 pub struct MessageBuilder(Message);
@@ -32,69 +36,128 @@ impl Message {
             panic!("Editing a message that was never sent");
         };
 
-        let body = format!("{{\"content\":{text:?}}}");
+        let body = payload(&text);
         self.content.replace(text);
 
-        #[allow(clippy::blocks_in_conditions)]
-        loop {
-            if {
-                #[cfg(feature = "minreq")]
-                {
-                    let body = body.clone();
-                    minreq::patch(format!("{}/messages/{id}", hook.0))
-                        .with_header("Content-Type", "application/json")
-                        .with_header("Content-Length", body.len().to_string())
-                        .with_body(body)
-                        .send()
-                        .is_ok()
-                }
-                #[cfg(feature = "ureq")]
-                {
-                    ureq::patch(&format!("{}/messages/{id}", hook.0))
-                        .set("Content-Type", "application/json")
-                        .set("Content-Length", &body.len().to_string())
-                        .send_string(&body)
-                        .is_ok()
-                }
-            } {
+        let request = Request::new(Method::Patch, format!("{}/messages/{id}", hook.url))
+            .body("application/json", body.into_bytes());
+        while let Err(why) = http::execute(&*hook.client, &request, None, logger) {
+            if signal::stopping() {
+                logger.error(&format!(
+                    "Failed to edit message: {}",
+                    http::redact(&why.to_string())
+                ));
                 break;
             }
-
-            logger.info("Failed to edit message, retrying in 10 seconds..");
-            std::thread::sleep(Duration::from_secs(10));
+            logger.info(&format!(
+                "Failed to edit message: {}, retrying in 10 seconds..",
+                http::redact(&why.to_string())
+            ));
+            http::pause(Duration::from_secs(10));
         }
     }
 }
 
-struct ApiMessage {
-    id: String,
+/// JSON body setting the message content.
+fn payload(content: &str) -> String {
+    JsonValue::Object(std::collections::HashMap::from([(
+        "content".to_string(),
+        JsonValue::String(content.to_string()),
+    )]))
+    .stringify()
+    .expect("Failed to serialize message")
 }
 
-#[derive(Debug)]
-pub struct Webhook(String);
+pub struct Webhook {
+    url: String,
+    client: Arc<dyn HttpClient>,
+}
+impl std::fmt::Debug for Webhook {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_tuple("Webhook")
+            .field(&http::redact(&self.url))
+            .finish()
+    }
+}
 impl Webhook {
-    pub fn new(url: String) -> Self {
-        Self(url)
+    pub fn new(url: String, client: Arc<dyn HttpClient>) -> Self {
+        Self { url, client }
+    }
+
+    /// Download a file, e.g. a message attachment.
+    pub fn download<L: Logger>(&self, url: &str, log: &mut L) -> Result<Vec<u8>, String> {
+        http::execute(&*self.client, &Request::new(Method::Get, url), Some(5), log)
+            .map(|x| x.body)
+            .map_err(|why| http::redact(&why.to_string()))
     }
 
-    pub fn url(&self) -> &str {
-        &self.0
+    /// Attachments of a message sent by this webhook, as `(filename, url)`.
+    pub fn attachments<L: Logger>(
+        &self,
+        id: NonZeroU64,
+        log: &mut L,
+    ) -> Result<Vec<(String, String)>, String> {
+        let data = self.download(&format!("{}/messages/{id}", self.url), log)?;
+        let json: JsonValue = String::from_utf8_lossy(&data)
+            .parse()
+            .map_err(|why| format!("Failed to parse json: {why}"))?;
+
+        let Some(JsonValue::Array(attachments)) = json
+            .get::<std::collections::HashMap<String, JsonValue>>()
+            .and_then(|x| x.get("attachments"))
+        else {
+            return Err("Received invalid json, expected attachments".into());
+        };
+
+        Ok(attachments
+            .iter()
+            .filter_map(|x| match (&x["filename"], &x["url"]) {
+                (JsonValue::String(name), JsonValue::String(url)) => {
+                    Some((name.clone(), url.clone()))
+                }
+                _ => None,
+            })
+            .collect())
     }
 
     /// Send a message.
     ///
-    /// Will try indefinitely until success.
+    /// Will try indefinitely until success, unless the backup is stopping.
     pub fn send<L: Logger>(
         &self,
         message: impl Fn(MessageBuilder) -> MessageBuilder,
         logger: &mut L,
-    ) -> Message {
+    ) -> Result<Message, String> {
+        loop {
+            match self.try_send(&message, logger) {
+                Ok(x) => break Ok(x),
+                Err(why) if signal::stopping() => {
+                    logger.error(&format!("Failed to send message: {why}"));
+                    break Err(why);
+                }
+                Err(why) => {
+                    logger.error(&format!(
+                        "Failed to send message: {why}, retrying in 1 minute..."
+                    ));
+                    http::pause(Duration::from_secs(60));
+                }
+            }
+        }
+    }
+
+    /// Send a message, giving up if Discord refuses it, e.g. when the files
+    /// are too large.
+    pub fn try_send<L: Logger>(
+        &self,
+        message: impl Fn(MessageBuilder) -> MessageBuilder,
+        logger: &mut L,
+    ) -> Result<Message, String> {
         let mut message: Message = message(Default::default()).0;
 
         let mut bodies: Vec<Vec<u8>> = vec![];
 
         if let Some(x) = &message.content {
-            bodies.push(format!("Content-Disposition: form-data; name=\"payload_json\"\r\nContent-Type: application/json\r\n\r\n{{\"content\":{x:?}}}").into_bytes());
+            bodies.push(format!("Content-Disposition: form-data; name=\"payload_json\"\r\nContent-Type: application/json\r\n\r\n{}", payload(x)).into_bytes());
         }
 
         for (i, (name, bytes)) in message.files.iter().enumerate() {
@@ -141,87 +204,115 @@ impl Webhook {
             body[ptr..ptr + header.len()].copy_from_slice(&header);
         }
 
-        #[allow(clippy::blocks_in_conditions)]
-        loop {
-            match {
-                #[cfg(feature = "minreq")]
-                {
-                    let body = body.clone();
-                    minreq::post(format!("{}?wait=true", self.0))
-                        .with_header(
-                            "Content-Type",
-                            format!("multipart/form-data; boundary={boundary}"),
-                        )
-                        .with_header("Content-Length", body.len().to_string())
-                        .with_body(body)
-                        .send()
-                }
-                #[cfg(feature = "ureq")]
-                {
-                    ureq::post(&format!("{}?wait=true", self.0))
-                        .set(
-                            "Content-Type",
-                            &format!("multipart/form-data; boundary={boundary}"),
-                        )
-                        .set("Content-Length", &body.len().to_string())
-                        .send_bytes(&body)
-                }
-            } {
-                Ok(x) => {
-                    let parsed: ApiMessage = match {
-                        #[cfg(feature = "ureq")]
-                        {
-                            x.into_string()
-                        }
-                        #[cfg(feature = "minreq")]
-                        {
-                            x.as_str()
-                        }
-                    } {
-                        Ok(x) => match x.parse::<JsonValue>() {
-                            Ok(x) => match x {
-                                JsonValue::Object(x) => match x.get("id") {
-                                    Some(JsonValue::String(x)) => ApiMessage { id: x.to_owned() },
-                                    x => {
-                                        logger.error(&format!("Received invalid json, retrying in 5 minutes\n\nExpected string, found {x:?}"));
-                                        std::thread::sleep(Duration::from_secs(300));
-                                        continue;
-                                    }
-                                },
-                                x => {
-                                    logger.error(&format!("Received invalid json, retrying in 5 minutes\n\nExpected object, found {x:?}"));
-                                    std::thread::sleep(Duration::from_secs(300));
-                                    continue;
-                                }
-                            },
-                            Err(why) => {
-                                logger.error(&format!(
-                                    "Failed to parse json, retrying in 5 minutes...\n\n{why}"
-                                ));
-                                std::thread::sleep(Duration::from_secs(300));
-                                continue;
-                            }
-                        },
-                        Err(why) => {
-                            logger.error(&format!(
-                                "Failed to parse message, retrying in 5 minutes...\n\n{why}"
-                            ));
-                            std::thread::sleep(Duration::from_secs(300));
-                            continue;
-                        }
-                    };
-                    message
-                        .id
-                        .replace(parsed.id.parse().expect("Failed to parse a number"));
-                    break message;
-                }
-                Err(why) => {
-                    logger.error(&format!(
-                        "Error sending request: {why}, retrying in 1 minute..."
-                    ));
-                    std::thread::sleep(Duration::from_secs(60));
-                }
-            }
-        }
+        let request = Request::new(Method::Post, format!("{}?wait=true", self.url))
+            .body(&format!("multipart/form-data; boundary={boundary}"), body);
+        let response = http::execute(&*self.client, &request, None, logger)
+            .map_err(|why| http::redact(&why.to_string()))?;
+
+        let id = match String::from_utf8_lossy(&response.body).parse::<JsonValue>() {
+            Ok(JsonValue::Object(x)) => match x.get("id") {
+                Some(JsonValue::String(x)) => x.parse().ok(),
+                _ => None,
+            },
+            _ => None,
+        };
+        let Some(id) = id else {
+            return Err(format!(
+                "Received invalid json: {}",
+                String::from_utf8_lossy(&response.body)
+            ));
+        };
+
+        message.id.replace(id);
+        Ok(message)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::{http::mock::Mock, log::NullLogger};
+
+    fn webhook() -> (Arc<Mock>, Webhook) {
+        let mock = Arc::new(Mock::default());
+        let webhook = Webhook::new(Mock::WEBHOOK.into(), mock.clone());
+        (mock, webhook)
+    }
+
+    #[test]
+    fn send_and_edit() {
+        let (mock, webhook) = webhook();
+        let mut message = webhook
+            .send(
+                |x| {
+                    x.content("Uploading \"backup\"...")
+                        .file("a.bin", vec![0, 1, 2])
+                        .file("b.txt", b"hello".to_vec())
+                },
+                &mut NullLogger,
+            )
+            .unwrap();
+
+        let id = message.id.unwrap().get();
+        let sent = mock.message(id).unwrap();
+        assert_eq!(sent.content.as_deref(), Some("Uploading \"backup\"..."));
+        assert_eq!(
+            sent.files,
+            [
+                ("a.bin".to_string(), vec![0, 1, 2]),
+                ("b.txt".to_string(), b"hello".to_vec())
+            ]
+        );
+
+        message.edit(&webhook, "Done", &mut NullLogger);
+        assert_eq!(mock.message(id).unwrap().content.as_deref(), Some("Done"));
+
+        assert!(mock.requests().iter().all(|x| x
+            .headers
+            .iter()
+            .any(|(name, value)| name == "User-Agent" && value == http::USER_AGENT)));
+    }
+
+    #[test]
+    fn attachments_and_download() {
+        let (_, webhook) = webhook();
+        let message = webhook
+            .send(|x| x.file("a.bin", vec![7; 10]), &mut NullLogger)
+            .unwrap();
+
+        let attachments = webhook
+            .attachments(message.id.unwrap(), &mut NullLogger)
+            .unwrap();
+        assert_eq!(attachments.len(), 1);
+        assert_eq!(attachments[0].0, "a.bin");
+        assert_eq!(
+            webhook.download(&attachments[0].1, &mut NullLogger),
+            Ok(vec![7; 10])
+        );
+    }
+
+    #[test]
+    fn rate_limit_is_retried() {
+        let (mock, webhook) = webhook();
+        mock.queue(429, r#"{"retry_after": 0}"#);
+
+        let message = webhook.try_send(|x| x.content("hi"), &mut NullLogger);
+        assert!(message.is_ok());
+        assert_eq!(mock.requests().len(), 2);
+    }
+
+    #[test]
+    fn client_errors_are_not_retried() {
+        let (mock, webhook) = webhook();
+        mock.queue(413, "request entity too large");
+
+        let Err(error) = webhook.try_send(|x| x.file("big", vec![0; 16]), &mut NullLogger) else {
+            panic!("413 was not an error");
+        };
+        assert!(error.contains("413"), "{error}");
+        assert!(!error.contains("token"), "{error}");
+        assert_eq!(mock.requests().len(), 1);
     }
 }
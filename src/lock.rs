@@ -0,0 +1,116 @@
+//! Keeps two instances from backing up the same job at once, e.g. a cron
+//! `--once` run overlapping the daemon.
+//!
+//! The lock is an OS file lock on a file in a dir only we can write to, see
+//! [temp::runtime_dir], so it goes away with the process holding it, even if
+//! that process crashed.
+
+use std::{
+    fs::{File, TryLockError},
+    io::{Read, Seek, Write},
+    time::Duration,
+};
+
+use crate::{log::Logger, signal, temp};
+
+/// What to do if another instance is running the same job.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LockMode {
+    Fail,
+    Wait,
+    Off,
+}
+impl LockMode {
+    pub fn parse(value: &str) -> Option<Self> {
+        match value {
+            "fail" => Some(Self::Fail),
+            "wait" => Some(Self::Wait),
+            "off" => Some(Self::Off),
+            _ => None,
+        }
+    }
+}
+
+/// Held lock, released when dropped.
+pub struct Lock {
+    _file: File,
+}
+
+/// Process id written to the lock file by its last holder.
+fn holder(file: &mut File) -> Option<u32> {
+    let mut data = String::new();
+    file.rewind().ok()?;
+    file.read_to_string(&mut data).ok()?;
+    data.trim().parse().ok()
+}
+
+/// Take the lock for the job identified by `key`, `None` if locking is off.
+pub fn acquire<L: Logger>(key: &str, mode: LockMode, log: &mut L) -> Result<Option<Lock>, String> {
+    if mode == LockMode::Off {
+        return Ok(None);
+    }
+
+    let path =
+        temp::job_file(key, "lock").map_err(|why| format!("failed to set up lock dir: {why}"))?;
+    let mut options = File::options();
+    options.read(true).write(true).create(true).truncate(false);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600).custom_flags(libc::O_NOFOLLOW);
+    }
+    let mut file = options
+        .open(&path)
+        .map_err(|why| format!("failed to open lock file {path:?}: {why}"))?;
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::MetadataExt;
+
+        let meta = file
+            .metadata()
+            .map_err(|why| format!("failed to open lock file {path:?}: {why}"))?;
+        if !meta.is_file() || meta.uid() != unsafe { libc::geteuid() } {
+            return Err(format!("lock file {path:?} is not a file of ours"));
+        }
+    }
+
+    let mut waiting = false;
+    loop {
+        match file.try_lock() {
+            Ok(()) => break,
+            Err(TryLockError::WouldBlock) => {
+                let pid = holder(&mut file).map_or("unknown".to_string(), |x| x.to_string());
+                if mode == LockMode::Fail {
+                    return Err(format!(
+                        "the job is already running in process {pid}, lock file {path:?}"
+                    ));
+                }
+                if signal::stopping() {
+                    return Err("interrupted while waiting for the lock".into());
+                }
+                if !waiting {
+                    log.info(&format!(
+                        "The job is already running in process {pid}, waiting for it to finish..."
+                    ));
+                    waiting = true;
+                }
+                std::thread::sleep(Duration::from_millis(500));
+            }
+            Err(TryLockError::Error(why)) => {
+                return Err(format!("failed to lock {path:?}: {why}"));
+            }
+        }
+    }
+
+    let written = file
+        .set_len(0)
+        .and_then(|_| file.rewind())
+        .and_then(|_| write!(file, "{}", std::process::id()))
+        .and_then(|_| file.flush());
+    if let Err(why) = written {
+        return Err(format!("failed to write lock file {path:?}: {why}"));
+    }
+
+    Ok(Some(Lock { _file: file }))
+}
@@ -1,66 +1,471 @@
 #![allow(dead_code)]
 
-use std::ops::DerefMut;
+use std::{
+    fs::File,
+    io::{IsTerminal, Write},
+    ops::DerefMut,
+    path::{Path, PathBuf},
+    sync::OnceLock,
+    time::{SystemTime, UNIX_EPOCH},
+};
 
-pub trait Logger {
-    fn info(&mut self, value: &str);
-    fn warn(&mut self, value: &str);
-    fn error(&mut self, value: &str);
+use tinyjson::JsonValue;
+
+use crate::time;
+
+/// Severity of a message, from most to least important.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Level {
+    Error,
+    Warn,
+    Info,
+    Debug,
+    Trace,
 }
-impl Logger for Box<dyn Logger> {
-    fn info(&mut self, value: &str) {
-        self.deref_mut().info(value)
+impl Level {
+    pub fn parse(value: &str) -> Option<Self> {
+        match value {
+            "error" => Some(Self::Error),
+            "warn" => Some(Self::Warn),
+            "info" => Some(Self::Info),
+            "debug" => Some(Self::Debug),
+            "trace" => Some(Self::Trace),
+            _ => None,
+        }
     }
-    fn warn(&mut self, value: &str) {
-        self.deref_mut().warn(value)
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Error => "error",
+            Self::Warn => "warn",
+            Self::Info => "info",
+            Self::Debug => "debug",
+            Self::Trace => "trace",
+        }
     }
-    fn error(&mut self, value: &str) {
-        self.deref_mut().error(value)
+
+    /// ANSI color of the level tag.
+    fn color(self) -> &'static str {
+        match self {
+            Self::Error => "31",
+            Self::Warn => "33",
+            Self::Info => "32",
+            Self::Debug => "34",
+            Self::Trace => "90",
+        }
     }
 }
-impl<T: Logger> Logger for &mut [T] {
-    fn info(&mut self, value: &str) {
-        self.iter_mut().for_each(|x| x.info(value));
+
+pub trait Logger {
+    fn log(&mut self, level: Level, value: &str);
+
+    fn error(&mut self, value: &str) {
+        self.log(Level::Error, value)
     }
     fn warn(&mut self, value: &str) {
-        self.iter_mut().for_each(|x| x.warn(value));
+        self.log(Level::Warn, value)
     }
-    fn error(&mut self, value: &str) {
-        self.iter_mut().for_each(|x| x.error(value));
+    fn info(&mut self, value: &str) {
+        self.log(Level::Info, value)
+    }
+    fn debug(&mut self, value: &str) {
+        self.log(Level::Debug, value)
+    }
+    fn trace(&mut self, value: &str) {
+        self.log(Level::Trace, value)
     }
 }
-impl<T: Logger> Logger for Vec<T> {
-    fn info(&mut self, value: &str) {
-        self.iter_mut().for_each(|x| x.info(value));
+impl Logger for Box<dyn Logger> {
+    fn log(&mut self, level: Level, value: &str) {
+        self.deref_mut().log(level, value)
     }
-    fn warn(&mut self, value: &str) {
-        self.iter_mut().for_each(|x| x.warn(value));
+}
+impl<T: Logger> Logger for &mut [T] {
+    fn log(&mut self, level: Level, value: &str) {
+        self.iter_mut().for_each(|x| x.log(level, value));
     }
-    fn error(&mut self, value: &str) {
-        self.iter_mut().for_each(|x| x.error(value));
+}
+impl<T: Logger> Logger for Vec<T> {
+    fn log(&mut self, level: Level, value: &str) {
+        self.iter_mut().for_each(|x| x.log(level, value));
     }
 }
 impl<T: Logger> Logger for Box<[T]> {
-    fn info(&mut self, value: &str) {
-        self.iter_mut().for_each(|x| x.info(value));
+    fn log(&mut self, level: Level, value: &str) {
+        self.iter_mut().for_each(|x| x.log(level, value));
     }
-    fn warn(&mut self, value: &str) {
-        self.iter_mut().for_each(|x| x.warn(value));
+}
+
+/// Discards everything.
+pub struct NullLogger;
+impl Logger for NullLogger {
+    fn log(&mut self, _: Level, _: &str) {}
+}
+
+/// Local time like `2024-05-01 13:37:00`.
+fn local_time() -> String {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|x| x.as_secs() as i64)
+        .unwrap_or_default();
+    let (year, month, day, hour, minute, second) = time::civil(now + time::local_offset(now));
+    format!("{year}-{month:02}-{day:02} {hour:02}:{minute:02}:{second:02}")
+}
+
+/// UTC time in RFC 3339 with milliseconds, for machines.
+fn utc_time() -> String {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    let (year, month, day, hour, minute, second) = time::civil(now.as_secs() as i64);
+    format!(
+        "{year}-{month:02}-{day:02}T{hour:02}:{minute:02}:{second:02}.{:03}Z",
+        now.subsec_millis()
+    )
+}
+
+fn text_line(level: Level, value: &str, timestamp: bool, color: bool) -> String {
+    let tag = format!("{:<5}", level.as_str().to_uppercase());
+    let tag = if color {
+        format!("\x1b[{}m{tag}\x1b[0m", level.color())
+    } else {
+        tag
+    };
+    if timestamp {
+        format!("{} {tag} {value}\n", local_time())
+    } else {
+        format!("{tag} {value}\n")
     }
-    fn error(&mut self, value: &str) {
-        self.iter_mut().for_each(|x| x.error(value));
+}
+
+fn json_line(level: Level, value: &str) -> String {
+    let line = JsonValue::Object(
+        [
+            ("time", JsonValue::String(utc_time())),
+            ("level", JsonValue::String(level.as_str().into())),
+            ("message", JsonValue::String(value.into())),
+        ]
+        .into_iter()
+        .map(|(k, v)| (k.to_string(), v))
+        .collect(),
+    );
+    format!("{}\n", line.stringify().unwrap_or_default())
+}
+
+/// Human readable lines on the terminal: warnings and errors go to stderr,
+/// everything else to stdout.
+pub struct ConsoleLogger {
+    pub level: Level,
+    pub timestamps: bool,
+    pub color: bool,
+}
+impl ConsoleLogger {
+    /// Colors if both streams are terminals and `NO_COLOR` is unset,
+    /// timestamps unless journald adds its own.
+    pub fn new(level: Level, color: Color) -> Self {
+        Self {
+            level,
+            timestamps: std::env::var_os("JOURNAL_STREAM").is_none(),
+            color: match color {
+                Color::Always => true,
+                Color::Never => false,
+                Color::Auto => {
+                    std::env::var_os("NO_COLOR").is_none_or(|x| x.is_empty())
+                        && std::io::stdout().is_terminal()
+                        && std::io::stderr().is_terminal()
+                }
+            },
+        }
+    }
+}
+impl Logger for ConsoleLogger {
+    fn log(&mut self, level: Level, value: &str) {
+        if level > self.level {
+            return;
+        }
+        let line = text_line(level, value, self.timestamps, self.color);
+        // Nowhere left to complain to if the terminal is gone
+        let _ = if level <= Level::Warn {
+            std::io::stderr().write_all(line.as_bytes())
+        } else {
+            std::io::stdout().write_all(line.as_bytes())
+        };
     }
 }
 
-pub struct ColorlessPrintlnLogger;
-impl Logger for ColorlessPrintlnLogger {
-    fn info(&mut self, value: &str) {
-        println!("{value}");
+/// One JSON object per line with `time`, `level` and `message`, for log
+/// shippers.
+pub struct JsonLogger<W: Write> {
+    pub level: Level,
+    pub out: W,
+}
+impl<W: Write> Logger for JsonLogger<W> {
+    fn log(&mut self, level: Level, value: &str) {
+        if level > self.level {
+            return;
+        }
+        let _ = self.out.write_all(json_line(level, value).as_bytes());
+        let _ = self.out.flush();
     }
-    fn warn(&mut self, value: &str) {
-        println!("{value}");
+}
+
+/// Appends to a file, which is rotated to `<path>.1`, `<path>.2`, ... once
+/// it grows past `max_size`.
+pub struct FileLogger {
+    level: Level,
+    format: Format,
+    path: PathBuf,
+    file: File,
+    size: u64,
+    max_size: u64,
+    keep: usize,
+}
+impl FileLogger {
+    pub fn open(
+        path: &Path,
+        level: Level,
+        format: Format,
+        max_size: u64,
+        keep: usize,
+    ) -> Result<Self, String> {
+        let file = Self::create(path)?;
+        let size = file.metadata().map(|x| x.len()).unwrap_or_default();
+        Ok(Self {
+            level,
+            format,
+            path: path.to_path_buf(),
+            file,
+            size,
+            max_size,
+            keep,
+        })
     }
-    fn error(&mut self, value: &str) {
-        println!("{value}");
+
+    fn create(path: &Path) -> Result<File, String> {
+        let mut options = File::options();
+        options.append(true).create(true);
+        // Logs show what's backed up, keep them private
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+        options
+            .open(path)
+            .map_err(|why| format!("failed to open log file {path:?}: {why}"))
+    }
+
+    fn rotated(&self, n: usize) -> PathBuf {
+        let mut path = self.path.as_os_str().to_owned();
+        path.push(format!(".{n}"));
+        PathBuf::from(path)
+    }
+
+    fn rotate(&mut self) -> Result<(), String> {
+        let _ = std::fs::remove_file(self.rotated(self.keep));
+        for n in (1..self.keep).rev() {
+            let _ = std::fs::rename(self.rotated(n), self.rotated(n + 1));
+        }
+        if self.keep == 0 {
+            let _ = std::fs::remove_file(&self.path);
+        } else {
+            let _ = std::fs::rename(&self.path, self.rotated(1));
+        }
+        self.file = Self::create(&self.path)?;
+        self.size = 0;
+        Ok(())
+    }
+}
+impl Logger for FileLogger {
+    fn log(&mut self, level: Level, value: &str) {
+        if level > self.level {
+            return;
+        }
+        let line = match self.format {
+            Format::Text => text_line(level, value, true, false),
+            Format::Json => json_line(level, value),
+        };
+        if self.size > 0 && self.size + line.len() as u64 > self.max_size {
+            if let Err(why) = self.rotate() {
+                eprintln!("{why}");
+                return;
+            }
+        }
+        if self.file.write_all(line.as_bytes()).is_ok() {
+            self.size += line.len() as u64;
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Text,
+    Json,
+}
+impl Format {
+    pub fn parse(value: &str) -> Option<Self> {
+        match value {
+            "text" => Some(Self::Text),
+            "json" => Some(Self::Json),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Color {
+    Auto,
+    Always,
+    Never,
+}
+impl Color {
+    pub fn parse(value: &str) -> Option<Self> {
+        match value {
+            "auto" => Some(Self::Auto),
+            "always" => Some(Self::Always),
+            "never" => Some(Self::Never),
+            _ => None,
+        }
+    }
+}
+
+pub const DEFAULT_MAX_SIZE: u64 = 10 * 1024 * 1024;
+pub const DEFAULT_KEEP: usize = 5;
+
+/// Where and how to log, from the config and the command line.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Options {
+    pub level: Level,
+    pub format: Format,
+    pub color: Color,
+    pub file: Option<PathBuf>,
+    pub max_size: u64,
+    pub keep: usize,
+}
+impl Default for Options {
+    fn default() -> Self {
+        Self {
+            level: Level::Info,
+            format: Format::Text,
+            color: Color::Auto,
+            file: None,
+            max_size: DEFAULT_MAX_SIZE,
+            keep: DEFAULT_KEEP,
+        }
+    }
+}
+
+/// Command line flags, which take precedence over the config.
+#[derive(Debug, Default)]
+pub struct Overrides {
+    pub level: Option<Level>,
+    pub format: Option<Format>,
+    pub color: Option<Color>,
+    pub file: Option<PathBuf>,
+}
+
+static OVERRIDES: OnceLock<Overrides> = OnceLock::new();
+
+pub fn set_overrides(overrides: Overrides) {
+    let _ = OVERRIDES.set(overrides);
+}
+
+impl Options {
+    /// Apply the command line flags, also after reloading the config.
+    pub fn with_overrides(mut self) -> Self {
+        if let Some(x) = OVERRIDES.get() {
+            self.level = x.level.unwrap_or(self.level);
+            self.format = x.format.unwrap_or(self.format);
+            self.color = x.color.unwrap_or(self.color);
+            if let Some(file) = &x.file {
+                self.file = Some(file.clone());
+            }
+        }
+        self
+    }
+
+    /// Console output, plus the log file if one is set.
+    pub fn build(&self) -> Result<Vec<Box<dyn Logger>>, String> {
+        let mut loggers: Vec<Box<dyn Logger>> = vec![match self.format {
+            Format::Text => Box::new(ConsoleLogger::new(self.level, self.color)),
+            Format::Json => Box::new(JsonLogger {
+                level: self.level,
+                out: std::io::stdout(),
+            }),
+        }];
+        if let Some(path) = &self.file {
+            loggers.push(Box::new(FileLogger::open(
+                path,
+                self.level,
+                self.format,
+                self.max_size,
+                self.keep,
+            )?));
+        }
+        Ok(loggers)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn levels_filter() {
+        let mut out = JsonLogger {
+            level: Level::Warn,
+            out: vec![],
+        };
+        out.error("a");
+        out.warn("b");
+        out.info("c");
+        out.debug("d");
+        let out = String::from_utf8(out.out).unwrap();
+        assert_eq!(out.lines().count(), 2, "{out}");
+        assert!(out.contains(r#""level":"error""#), "{out}");
+        assert!(out.contains(r#""message":"b""#), "{out}");
+    }
+
+    #[test]
+    fn json_lines_escape() {
+        let line = json_line(Level::Info, "two\nlines \"quoted\"");
+        assert_eq!(line.lines().count(), 1);
+        let JsonValue::Object(x) = line.trim().parse::<JsonValue>().unwrap() else {
+            panic!("{line}");
+        };
+        assert_eq!(
+            x["message"],
+            JsonValue::String("two\nlines \"quoted\"".into())
+        );
+        assert!(matches!(&x["time"], JsonValue::String(x) if x.ends_with('Z')));
+    }
+
+    #[test]
+    fn text_lines() {
+        assert_eq!(text_line(Level::Warn, "hi", false, false), "WARN  hi\n");
+        assert_eq!(
+            text_line(Level::Error, "hi", false, true),
+            "\x1b[31mERROR\x1b[0m hi\n"
+        );
+    }
+
+    #[test]
+    fn file_rotates() {
+        let dir = std::env::temp_dir().join(format!("dbu-log-test-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("backup.log");
+
+        let mut log = FileLogger::open(&path, Level::Info, Format::Text, 100, 2).unwrap();
+        for n in 0..20 {
+            log.info(&format!("message number {n}"));
+        }
+        drop(log);
+
+        let current = std::fs::read_to_string(&path).unwrap();
+        assert!(current.len() <= 100, "{current}");
+        assert!(current.contains("message number 19"), "{current}");
+        assert!(dir.join("backup.log.1").exists());
+        assert!(dir.join("backup.log.2").exists());
+        assert!(!dir.join("backup.log.3").exists());
+
+        std::fs::remove_dir_all(&dir).unwrap();
     }
 }
@@ -1,18 +1,37 @@
-use std::ops::{Deref, DerefMut};
+use std::{
+    ops::{Deref, DerefMut},
+    time::{Instant, SystemTime},
+};
 
-use config::parse_args;
-use log::ColorlessPrintlnLogger;
-use upload::upload;
+use config::{parse_args, Config, Mode};
+use log::Logger;
+use signal::Event;
+use state::State;
+use upload::{upload, Run};
 
 #[cfg(not(any(feature = "ureq", feature = "minreq")))]
 compile_error!("Either 'ureq' or 'minreq' feature must be enabled");
-#[cfg(all(feature = "ureq", feature = "minreq"))]
-compile_error!("Cannot enable both 'ureq' and 'minreq' features");
 
+mod archive;
 mod config;
+mod control;
+mod crypt;
+mod glob;
+mod healthcheck;
 mod hook;
+mod http;
+mod lock;
 mod log;
+mod manifest;
+mod metrics;
+mod notify;
+mod restore;
+mod secret;
+mod signal;
+mod state;
 mod temp;
+mod throttle;
+mod time;
 mod upload;
 
 struct Defer<T, G, F: Fn(&mut T) -> G>(T, F);
@@ -49,20 +68,220 @@ impl<I, T: AsMut<I>, G, F: Fn(&mut T) -> G> AsMut<I> for Defer<T, G, F> {
     }
 }
 
+/// Back up as the next run of the job and remember how it went.
+fn run_backup<L: Logger>(config: &Config, state: &mut State, log: &mut L) -> bool {
+    let run = Run {
+        id: state.runs + 1,
+        started_at: SystemTime::now(),
+        last_success: state.last_success,
+    };
+    let success = upload(config, &run, log);
+
+    state.runs = run.id;
+    if success {
+        state.last_success = Some(run.started_at);
+    }
+    if let Err(why) = state.save() {
+        log.warn(&format!("Failed to save the job state: {why}"));
+    }
+    success
+}
+
 fn main() {
-    let config = Box::leak(Box::new(parse_args()));
+    notify::init();
+    let (config, mode) = parse_args();
+    let config = Box::leak(Box::new(config));
+
+    let mut logger = match config.log.build() {
+        Ok(x) => x,
+        Err(why) => {
+            eprintln!("Not starting, {why}");
+            std::process::exit(-1);
+        }
+    };
+
+    if let Mode::Control(command) = &mode {
+        let Some(path) = &config.control_socket else {
+            logger.error(&format!("control-socket is not set in {:?}", config.path));
+            std::process::exit(-1);
+        };
+        if let Err(why) = control::run(path, command) {
+            logger.error(&why);
+            std::process::exit(-1);
+        }
+        return;
+    }
+
+    if let Some(x) = &config.temp_dir {
+        temp::set_root(x.clone());
+    }
+    temp::cleanup(&mut logger);
+
+    if let Mode::Restore {
+        archive,
+        destination,
+        identity,
+        no_verify,
+    } = mode
+    {
+        if let Err(why) = restore::restore(
+            config,
+            &archive,
+            &destination,
+            identity.as_deref(),
+            no_verify,
+            &mut logger,
+        ) {
+            logger.error(&why);
+            std::process::exit(-1);
+        }
+        return;
+    }
+
+    signal::install();
+
+    let lock = match lock::acquire(&config.lock_key, config.lock, &mut logger) {
+        Ok(x) => x,
+        Err(why) => {
+            logger.error(&format!("Not starting, {why}"));
+            std::process::exit(-1);
+        }
+    };
+    metrics::set_job(&config.name);
+    if let Some(x) = &config.metrics_file {
+        metrics::load(x);
+    }
+    // Only read once the lock is ours, another instance may have just run
+    let state_path = state::path(config);
+    if state_path.is_none() {
+        logger.warn("Nowhere to keep the run count and last success, set state-file");
+    }
+    let mut state = State::load(state_path);
+    notify::ready();
 
-    let mut logger = ColorlessPrintlnLogger;
+    if let Mode::Once = mode {
+        let success = run_backup(config, &mut state, &mut logger);
+        drop(lock);
+        if !success {
+            std::process::exit(-1);
+        }
+        return;
+    }
+
+    control::set_job(&config.name);
+    #[cfg(unix)]
+    let _control = match &config.control_socket {
+        Some(path) => match control::serve(path) {
+            Ok(x) => Some(x),
+            Err(why) => {
+                logger.error(&format!(
+                    "Not starting, failed to listen on {path:?}: {why}"
+                ));
+                std::process::exit(-1);
+            }
+        },
+        None => None,
+    };
+
+    if let Some(address) = config.metrics_listen {
+        if let Err(why) = metrics::serve(address) {
+            logger.error(&format!(
+                "Not starting, failed to serve metrics on {address}: {why}"
+            ));
+            std::process::exit(-1);
+        }
+    }
 
-    let mut first = true;
+    // Secrets were resolved when loading the config
+    let mut fresh = true;
+    let mut next = Instant::now();
+
+    let watchdog = notify::watchdog_interval();
 
     loop {
-        if first {
-            first = false;
-        } else {
-            std::thread::sleep(config.delay);
+        control::scheduled(next);
+        notify::watchdog();
+        let mut wait = next.saturating_duration_since(Instant::now());
+        if let Some(x) = watchdog {
+            wait = wait.min(x);
+        }
+
+        match signal::wait(wait) {
+            Some(Event::Stop) => {
+                notify::stopping();
+                logger.info("Exiting");
+                return;
+            }
+            Some(Event::Reload) => {
+                notify::reloading();
+                match config::load(&config.path, &mode) {
+                    Ok(x) => {
+                        if x.temp_dir != config.temp_dir {
+                            logger.warn("Changing temp-dir requires a restart");
+                        }
+                        if (lock.is_some() && x.lock_key != config.lock_key)
+                            || x.lock != config.lock
+                        {
+                            logger.warn("Changing the job name or lock requires a restart");
+                        }
+                        if x.state_file != config.state_file {
+                            logger.warn("Changing state-file requires a restart");
+                        }
+                        if x.control_socket != config.control_socket {
+                            logger.warn("Changing control-socket requires a restart");
+                        }
+                        if x.metrics_listen != config.metrics_listen {
+                            logger.warn("Changing metrics-listen requires a restart");
+                        }
+                        // Keep the schedule, but with the new delay
+                        next = next
+                            .checked_sub(config.delay)
+                            .map_or(next, |last| last + x.delay);
+                        if x.log != config.log {
+                            match x.log.build() {
+                                Ok(x) => logger = x,
+                                Err(why) => logger.error(&format!(
+                                    "Failed to set up logging, keeping the old settings: {why}"
+                                )),
+                            }
+                        }
+                        *config = x;
+                        control::set_job(&config.name);
+                        metrics::set_job(&config.name);
+                        logger.info("Reloaded config");
+                    }
+                    Err(why) => logger.error(&format!(
+                        "Failed to reload config, keeping the old one: {why}"
+                    )),
+                }
+                notify::ready();
+                continue;
+            }
+            Some(Event::Trigger) => logger.info("Starting backup early on request"),
+            // Woke up early to ping the watchdog
+            None if Instant::now() < next => continue,
+            None => (),
+        }
+
+        // Secrets may have been rotated since the last run
+        if !fresh {
+            if let Err(why) = config.resolve_secrets() {
+                logger.error(&format!("Skipping backup, {why}"));
+                next = Instant::now() + config.delay;
+                continue;
+            }
         }
 
-        upload(config, &mut logger);
+        fresh = false;
+        next = Instant::now() + config.delay;
+        control::scheduled(next);
+
+        run_backup(config, &mut state, &mut logger);
+
+        if signal::exiting() {
+            notify::stopping();
+            logger.info("Backup interrupted, exiting");
+            return;
+        }
     }
 }
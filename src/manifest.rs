@@ -0,0 +1,163 @@
+//! Manifests list the messages a backup was uploaded in along with their
+//! hashes, and can be signed with an Ed25519 key so a restore can tell
+//! whether anyone tampered with the channel.
+
+use std::{collections::HashMap, num::NonZeroU64};
+
+use ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey};
+use rand::RngCore;
+use sha2::{Digest, Sha256};
+use tinyjson::JsonValue;
+
+#[derive(Debug)]
+pub struct Manifest {
+    pub job: String,
+    /// Unix timestamp of when the backup was made.
+    pub created: u64,
+    /// Extension of the assembled archive, e.g. `tar.zst.enc`.
+    pub extension: String,
+    /// Messages holding the archive chunks and their SHA-256 hashes.
+    pub chunks: Vec<(NonZeroU64, String)>,
+    /// Message holding the download script and its SHA-256 hash, signed
+    /// backups don't have one.
+    pub script: Option<(NonZeroU64, String)>,
+}
+impl Manifest {
+    pub fn to_json(&self) -> String {
+        let entry = |(id, hash): &(NonZeroU64, String)| {
+            JsonValue::Object(HashMap::from([
+                ("message".to_string(), JsonValue::String(id.to_string())),
+                ("sha256".to_string(), JsonValue::String(hash.clone())),
+            ]))
+        };
+
+        let mut json = HashMap::from([
+            ("version".to_string(), JsonValue::Number(1.0)),
+            ("job".to_string(), JsonValue::String(self.job.clone())),
+            (
+                "created".to_string(),
+                JsonValue::Number(self.created as f64),
+            ),
+            (
+                "extension".to_string(),
+                JsonValue::String(self.extension.clone()),
+            ),
+            (
+                "chunks".to_string(),
+                JsonValue::Array(self.chunks.iter().map(entry).collect()),
+            ),
+        ]);
+        if let Some(script) = &self.script {
+            json.insert("script".to_string(), entry(script));
+        }
+        JsonValue::Object(json)
+            .stringify()
+            .expect("Failed to serialize manifest")
+    }
+
+    pub fn parse(data: &[u8]) -> Result<Self, String> {
+        let invalid = |what: &str| format!("invalid manifest: {what}");
+
+        let json: JsonValue = std::str::from_utf8(data)
+            .map_err(|_| invalid("not UTF-8"))?
+            .parse()
+            .map_err(|why| invalid(&format!("{why}")))?;
+        let JsonValue::Object(json) = json else {
+            return Err(invalid("expected an object"));
+        };
+
+        let string = |x: Option<&JsonValue>, what: &str| match x {
+            Some(JsonValue::String(x)) => Ok(x.clone()),
+            _ => Err(invalid(what)),
+        };
+        let entry = |x: Option<&JsonValue>, what: &str| {
+            let Some(JsonValue::Object(x)) = x else {
+                return Err(invalid(what));
+            };
+            let id = string(x.get("message"), what)?
+                .parse()
+                .map_err(|_| invalid(what))?;
+            Ok((id, string(x.get("sha256"), what)?))
+        };
+
+        if json.get("version") != Some(&JsonValue::Number(1.0)) {
+            return Err(invalid("unsupported version"));
+        }
+        let Some(JsonValue::Array(chunks)) = json.get("chunks") else {
+            return Err(invalid("missing chunks"));
+        };
+
+        Ok(Self {
+            job: string(json.get("job"), "missing job")?,
+            created: match json.get("created") {
+                Some(JsonValue::Number(x)) => *x as u64,
+                _ => return Err(invalid("missing created")),
+            },
+            extension: string(json.get("extension"), "missing extension")?,
+            chunks: chunks
+                .iter()
+                .map(|x| entry(Some(x), "invalid chunk"))
+                .collect::<Result<_, _>>()?,
+            script: match json.get("script") {
+                Some(x) => Some(entry(Some(x), "invalid script")?),
+                None => None,
+            },
+        })
+    }
+}
+
+pub fn sha256(data: &[u8]) -> String {
+    hex(&Sha256::digest(data))
+}
+
+fn hex(data: &[u8]) -> String {
+    data.iter().map(|x| format!("{x:02x}")).collect()
+}
+
+fn unhex<const N: usize>(value: &str) -> Option<[u8; N]> {
+    let value = value.trim();
+    if value.len() != N * 2 || !value.is_ascii() {
+        return None;
+    }
+    let mut data = [0u8; N];
+    for (i, x) in data.iter_mut().enumerate() {
+        *x = u8::from_str_radix(&value[i * 2..i * 2 + 2], 16).ok()?;
+    }
+    Some(data)
+}
+
+/// Generate a new `(signing key, verify key)` pair.
+pub fn keygen() -> (String, String) {
+    let mut seed = [0u8; 32];
+    rand::thread_rng().fill_bytes(&mut seed);
+    let key = SigningKey::from_bytes(&seed);
+    (hex(&seed), hex(key.verifying_key().as_bytes()))
+}
+
+pub fn signing_key(value: &str) -> Result<SigningKey, &'static str> {
+    unhex(value)
+        .map(|x| SigningKey::from_bytes(&x))
+        .ok_or("expected 64 hex characters")
+}
+
+pub fn verify_key(value: &str) -> Result<VerifyingKey, &'static str> {
+    VerifyingKey::from_bytes(&unhex(value).ok_or("expected 64 hex characters")?)
+        .map_err(|_| "not a valid Ed25519 public key")
+}
+
+/// Public half of a signing key, as used in `verify-key`.
+pub fn public_key(key: &SigningKey) -> String {
+    hex(key.verifying_key().as_bytes())
+}
+
+/// Hex encoded signature of `data`.
+pub fn sign(key: &SigningKey, data: &[u8]) -> String {
+    hex(&key.sign(data).to_bytes())
+}
+
+pub fn verify(key: &VerifyingKey, data: &[u8], signature: &[u8]) -> bool {
+    std::str::from_utf8(signature)
+        .ok()
+        .and_then(unhex::<64>)
+        .is_some_and(|x| key.verify(data, &Signature::from_bytes(&x)).is_ok())
+}
@@ -0,0 +1,263 @@
+//! Prometheus metrics, served on `/metrics` and/or written to a
+//! node_exporter textfile.
+
+use std::{
+    collections::BTreeMap,
+    fs,
+    io::{self, BufRead, BufReader, Write},
+    net::{SocketAddr, TcpListener},
+    path::Path,
+    sync::Mutex,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+/// Metrics of the job, all as floats like Prometheus has them.
+struct Metrics {
+    job: String,
+    last_success: Option<f64>,
+    last_run: Option<f64>,
+    last_duration: Option<f64>,
+    last_archive_bytes: Option<f64>,
+    last_chunks: Option<f64>,
+    runs: f64,
+    retries: f64,
+    rate_limited: f64,
+    /// Failures by stage.
+    failures: BTreeMap<String, f64>,
+}
+
+static METRICS: Mutex<Metrics> = Mutex::new(Metrics {
+    job: String::new(),
+    last_success: None,
+    last_run: None,
+    last_duration: None,
+    last_archive_bytes: None,
+    last_chunks: None,
+    runs: 0.0,
+    retries: 0.0,
+    rate_limited: 0.0,
+    failures: BTreeMap::new(),
+});
+
+fn unix(time: SystemTime) -> f64 {
+    time.duration_since(UNIX_EPOCH)
+        .map(|x| x.as_secs_f64())
+        .unwrap_or_default()
+}
+
+pub fn set_job(name: &str) {
+    METRICS.lock().unwrap().job = name.to_string();
+}
+
+/// A request is retried.
+pub fn retried() {
+    METRICS.lock().unwrap().retries += 1.0;
+}
+
+/// Discord answered with 429.
+pub fn rate_limited() {
+    METRICS.lock().unwrap().rate_limited += 1.0;
+}
+
+/// A backup that started at `started` and took `duration` finished, with
+/// the archive size and chunk count if it was published and the failed stage
+/// otherwise.
+pub fn finished(started: SystemTime, duration: Duration, result: Result<(u64, usize), &str>) {
+    let mut metrics = METRICS.lock().unwrap();
+    metrics.runs += 1.0;
+    metrics.last_run = Some(unix(started));
+    metrics.last_duration = Some(duration.as_secs_f64());
+    match result {
+        Ok((bytes, chunks)) => {
+            metrics.last_success = Some(unix(started));
+            metrics.last_archive_bytes = Some(bytes as f64);
+            metrics.last_chunks = Some(chunks as f64);
+        }
+        Err(stage) => *metrics.failures.entry(stage.to_string()).or_default() += 1.0,
+    }
+}
+
+/// Escape a label value.
+fn label(value: &str) -> String {
+    value
+        .replace('\\', r"\\")
+        .replace('"', r#"\""#)
+        .replace('\n', r"\n")
+}
+
+/// Metrics in the Prometheus text format.
+pub fn render() -> String {
+    let metrics = METRICS.lock().unwrap();
+    let job = format!("job=\"{}\"", label(&metrics.job));
+    let mut out = String::new();
+    let mut metric = |name: &str, kind: &str, help: &str, samples: &[(String, f64)]| {
+        out += &format!("# HELP {name} {help}\n# TYPE {name} {kind}\n");
+        for (labels, value) in samples {
+            out += &format!("{name}{{{labels}}} {value}\n");
+        }
+    };
+    let gauge = |x: Option<f64>| x.map(|x| vec![(job.clone(), x)]).unwrap_or_default();
+
+    metric(
+        "discord_backup_last_success_timestamp_seconds",
+        "gauge",
+        "When the last successful backup started.",
+        &gauge(metrics.last_success),
+    );
+    metric(
+        "discord_backup_last_run_timestamp_seconds",
+        "gauge",
+        "When the last backup started.",
+        &gauge(metrics.last_run),
+    );
+    metric(
+        "discord_backup_last_duration_seconds",
+        "gauge",
+        "How long the last backup took.",
+        &gauge(metrics.last_duration),
+    );
+    metric(
+        "discord_backup_last_archive_bytes",
+        "gauge",
+        "Size of the last published archive.",
+        &gauge(metrics.last_archive_bytes),
+    );
+    metric(
+        "discord_backup_last_chunks",
+        "gauge",
+        "Chunks the last published archive was split into.",
+        &gauge(metrics.last_chunks),
+    );
+    metric(
+        "discord_backup_runs_total",
+        "counter",
+        "Finished backups, successful or not.",
+        &[(job.clone(), metrics.runs)],
+    );
+    metric(
+        "discord_backup_failures_total",
+        "counter",
+        "Failed backups by the stage they failed in.",
+        &metrics
+            .failures
+            .iter()
+            .map(|(stage, x)| (format!("{job},stage=\"{}\"", label(stage)), *x))
+            .collect::<Vec<_>>(),
+    );
+    metric(
+        "discord_backup_http_retries_total",
+        "counter",
+        "Requests to Discord that were retried.",
+        &[(job.clone(), metrics.retries)],
+    );
+    metric(
+        "discord_backup_http_rate_limited_total",
+        "counter",
+        "Requests to Discord that were rate limited.",
+        &[(job.clone(), metrics.rate_limited)],
+    );
+
+    out
+}
+
+/// Pick up the job's values from a textfile written by an earlier run, so
+/// counters and the last success survive `--once` runs and restarts.
+pub fn load(path: &Path) {
+    let Ok(data) = fs::read_to_string(path) else {
+        return;
+    };
+
+    let mut metrics = METRICS.lock().unwrap();
+    let job = format!("job=\"{}\"", label(&metrics.job));
+    for line in data.lines().filter(|x| !x.starts_with('#')) {
+        let Some((series, value)) = line.rsplit_once(' ') else {
+            continue;
+        };
+        let Ok(value) = value.parse::<f64>() else {
+            continue;
+        };
+        let Some((name, labels)) = series.strip_suffix('}').and_then(|x| x.split_once('{')) else {
+            continue;
+        };
+        let rest = if labels == job {
+            None
+        } else if let Some(x) = labels.strip_prefix(&format!("{job},")) {
+            Some(x)
+        } else {
+            continue;
+        };
+
+        match (name, rest) {
+            ("discord_backup_last_success_timestamp_seconds", None) => {
+                metrics.last_success = Some(value)
+            }
+            ("discord_backup_last_run_timestamp_seconds", None) => metrics.last_run = Some(value),
+            ("discord_backup_last_duration_seconds", None) => metrics.last_duration = Some(value),
+            ("discord_backup_last_archive_bytes", None) => metrics.last_archive_bytes = Some(value),
+            ("discord_backup_last_chunks", None) => metrics.last_chunks = Some(value),
+            ("discord_backup_runs_total", None) => metrics.runs = value,
+            ("discord_backup_http_retries_total", None) => metrics.retries = value,
+            ("discord_backup_http_rate_limited_total", None) => metrics.rate_limited = value,
+            ("discord_backup_failures_total", Some(stage)) => {
+                if let Some(stage) = stage
+                    .strip_prefix("stage=\"")
+                    .and_then(|x| x.strip_suffix('"'))
+                {
+                    metrics.failures.insert(stage.to_string(), value);
+                }
+            }
+            _ => (),
+        }
+    }
+}
+
+/// Write the metrics to `path` for node_exporter's textfile collector.
+pub fn write(path: &Path) -> io::Result<()> {
+    // The collector must never see a half-written file
+    let mut temp = path.as_os_str().to_owned();
+    temp.push(".tmp");
+    fs::write(&temp, render())?;
+    fs::rename(&temp, path)
+}
+
+/// Serve the metrics on `address` in the background.
+pub fn serve(address: SocketAddr) -> io::Result<()> {
+    let listener = TcpListener::bind(address)?;
+
+    std::thread::spawn(move || {
+        for stream in listener.incoming() {
+            let Ok(mut stream) = stream else {
+                continue;
+            };
+            let _ = stream.set_read_timeout(Some(Duration::from_secs(5)));
+            let _ = stream.set_write_timeout(Some(Duration::from_secs(5)));
+
+            let mut reader = BufReader::new(&stream);
+            let mut request = String::new();
+            if reader.read_line(&mut request).is_err() {
+                continue;
+            }
+            // Skip the headers
+            let mut line = String::new();
+            while reader.read_line(&mut line).is_ok_and(|x| x > 2) {
+                line.clear();
+            }
+
+            let path = request.split(' ').nth(1).unwrap_or_default();
+            let response = if request.starts_with("GET ")
+                && (path == "/metrics" || path.starts_with("/metrics?"))
+            {
+                let body = render();
+                format!(
+                    "HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+                    body.len()
+                )
+            } else {
+                "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n".into()
+            };
+            let _ = stream.write_all(response.as_bytes());
+        }
+    });
+
+    Ok(())
+}
@@ -0,0 +1,152 @@
+//! systemd service notifications, see sd_notify(3). Everything is a no-op
+//! unless systemd set `NOTIFY_SOCKET`.
+
+use std::time::Duration;
+
+#[cfg(unix)]
+mod imp {
+    use std::{
+        os::unix::net::{SocketAddr, UnixDatagram},
+        sync::{Mutex, OnceLock},
+        time::{Duration, Instant},
+    };
+
+    struct Socket {
+        socket: UnixDatagram,
+        address: SocketAddr,
+        watchdog: Option<Duration>,
+        last_ping: Mutex<Option<Instant>>,
+    }
+
+    static SOCKET: OnceLock<Option<Socket>> = OnceLock::new();
+
+    fn connect() -> Option<Socket> {
+        let path = std::env::var("NOTIFY_SOCKET").ok()?;
+        let address = match path.strip_prefix('@') {
+            #[cfg(target_os = "linux")]
+            Some(name) => {
+                use std::os::linux::net::SocketAddrExt;
+                SocketAddr::from_abstract_name(name).ok()?
+            }
+            _ => SocketAddr::from_pathname(&path).ok()?,
+        };
+
+        let watchdog = std::env::var("WATCHDOG_USEC")
+            .ok()
+            .and_then(|x| x.parse().ok())
+            .filter(|&x: &u64| x != 0)
+            .filter(|_| {
+                std::env::var("WATCHDOG_PID").map_or(true, |x| x.parse() == Ok(std::process::id()))
+            })
+            .map(Duration::from_micros);
+        // Only meant for us, not for the scripts we run
+        for x in ["NOTIFY_SOCKET", "WATCHDOG_USEC", "WATCHDOG_PID"] {
+            std::env::remove_var(x);
+        }
+
+        // Better to lose a notification than to hang if systemd is slow
+        let socket = UnixDatagram::unbound().ok()?;
+        socket.set_nonblocking(true).ok()?;
+
+        Some(Socket {
+            socket,
+            address,
+            watchdog,
+            last_ping: Mutex::new(None),
+        })
+    }
+
+    pub fn init() {
+        SOCKET.get_or_init(connect);
+    }
+
+    pub fn send(state: &str) {
+        if let Some(Some(x)) = SOCKET.get() {
+            let _ = x.socket.send_to_addr(state.as_bytes(), &x.address);
+        }
+    }
+
+    pub fn watchdog_interval() -> Option<Duration> {
+        SOCKET.get()?.as_ref()?.watchdog
+    }
+
+    pub fn watchdog() {
+        let Some(Some(x)) = SOCKET.get() else {
+            return;
+        };
+        let Some(timeout) = x.watchdog else {
+            return;
+        };
+
+        // Don't flood the socket when called for every file
+        let mut last = x.last_ping.lock().unwrap();
+        if last.is_some_and(|x| x.elapsed() < timeout / 4) {
+            return;
+        }
+        *last = Some(Instant::now());
+        let _ = x.socket.send_to_addr(b"WATCHDOG=1", &x.address);
+    }
+
+    pub fn monotonic_usec() -> u64 {
+        let mut time = std::mem::MaybeUninit::<libc::timespec>::uninit();
+        unsafe { libc::clock_gettime(libc::CLOCK_MONOTONIC, time.as_mut_ptr()) };
+        let time = unsafe { time.assume_init() };
+        // Field types differ between platforms
+        #[allow(clippy::unnecessary_cast)]
+        let usec = time.tv_sec as u64 * 1_000_000 + time.tv_nsec as u64 / 1000;
+        usec
+    }
+}
+
+#[cfg(windows)]
+mod imp {
+    use std::time::Duration;
+
+    pub fn init() {}
+    pub fn send(_: &str) {}
+    pub fn watchdog_interval() -> Option<Duration> {
+        None
+    }
+    pub fn watchdog() {}
+}
+
+/// Connect to the notification socket, must run before any threads start.
+pub fn init() {
+    imp::init();
+}
+
+pub fn ready() {
+    imp::send("READY=1");
+}
+
+pub fn reloading() {
+    #[cfg(unix)]
+    imp::send(&format!(
+        "RELOADING=1\nMONOTONIC_USEC={}",
+        imp::monotonic_usec()
+    ));
+}
+
+pub fn stopping() {
+    imp::send("STOPPING=1");
+}
+
+/// Show `text` in `systemctl status`, only its first line is used.
+pub fn status(text: &str) {
+    imp::send(&format!(
+        "STATUS={}",
+        text.lines().next().unwrap_or_default()
+    ));
+    watchdog();
+}
+
+/// How often [watchdog] should be called, if systemd is watching.
+pub fn watchdog_interval() -> Option<Duration> {
+    // Ping twice per timeout, as sd_watchdog_enabled(3) recommends
+    imp::watchdog_interval().map(|x| x / 2)
+}
+
+/// Tell systemd we're still making progress.
+pub fn watchdog() {
+    imp::watchdog();
+}
@@ -0,0 +1,401 @@
+use std::{
+    fs::{self, File},
+    io::{self, BufReader, BufWriter, Read, Seek, Write},
+    num::NonZeroU64,
+    path::{Path, PathBuf},
+};
+
+use zip::{extra_fields::ExtraField, read::ZipFile, ZipArchive};
+
+use crate::{
+    archive::Format,
+    config::Config,
+    crypt,
+    hook::Webhook,
+    log::Logger,
+    manifest::{self, Manifest},
+    temp::{self, temp_path},
+    time, Defer,
+};
+
+/// Metadata of an archive entry.
+struct Attributes {
+    mode: Option<u32>,
+    mtime: Option<i64>,
+    owner: Option<(u32, u32)>,
+}
+impl Attributes {
+    fn new(entry: &ZipFile) -> Self {
+        let mtime = entry
+            .extra_data_fields()
+            .find_map(|x| match x {
+                ExtraField::ExtendedTimestamp(x) => x.mod_time(),
+            })
+            .map(i64::from)
+            .or_else(|| {
+                entry.last_modified().map(|x| {
+                    time::unix(
+                        x.year().into(),
+                        x.month().into(),
+                        x.day().into(),
+                        x.hour().into(),
+                        x.minute().into(),
+                        x.second().into(),
+                    )
+                })
+            });
+
+        // Info-ZIP Unix (uid and gid)
+        let mut owner = None;
+        let mut extra = entry.extra_data().unwrap_or_default();
+        while extra.len() >= 4 {
+            let id = u16::from_le_bytes([extra[0], extra[1]]);
+            let len = u16::from_le_bytes([extra[2], extra[3]]) as usize;
+            let Some(data) = extra.get(4..4 + len) else {
+                break;
+            };
+            if id == 0x7875 && len == 11 && data[0] == 1 && data[1] == 4 && data[6] == 4 {
+                owner = Some((
+                    u32::from_le_bytes(data[2..6].try_into().unwrap()),
+                    u32::from_le_bytes(data[7..11].try_into().unwrap()),
+                ));
+            }
+            extra = &extra[4 + len..];
+        }
+
+        Self {
+            mode: entry.unix_mode(),
+            mtime,
+            owner,
+        }
+    }
+
+    fn apply<L: Logger>(&self, path: &Path, symlink: bool, log: &mut L) {
+        if let (Some(mtime), false) = (self.mtime, symlink) {
+            let mtime = std::time::UNIX_EPOCH + std::time::Duration::from_secs(mtime.max(0) as u64);
+            if let Err(why) = File::open(path).and_then(|x| x.set_modified(mtime)) {
+                log.warn(&format!("Failed to set mtime of {path:?}: {why}"));
+            }
+        }
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+
+            if let Some((uid, gid)) = self.owner {
+                match std::os::unix::fs::lchown(path, Some(uid), Some(gid)) {
+                    // Only root can give files away
+                    Err(why) if why.kind() != io::ErrorKind::PermissionDenied => {
+                        log.warn(&format!("Failed to set owner of {path:?}: {why}"));
+                    }
+                    _ => (),
+                }
+            }
+
+            if let (Some(mode), false) = (self.mode, symlink) {
+                if let Err(why) =
+                    fs::set_permissions(path, fs::Permissions::from_mode(mode & 0o7777))
+                {
+                    log.warn(&format!("Failed to set mode of {path:?}: {why}"));
+                }
+            }
+        }
+    }
+}
+
+/// Whether any parent of `name` inside `destination` is a symlink, writing
+/// through it could end up outside of `destination`.
+fn through_symlink(destination: &Path, name: &Path) -> bool {
+    let mut path = destination.to_path_buf();
+    let mut components = name.components().peekable();
+    while let Some(x) = components.next() {
+        if components.peek().is_none() {
+            break;
+        }
+        path.push(x);
+        if fs::symlink_metadata(&path).is_ok_and(|x| x.file_type().is_symlink()) {
+            return true;
+        }
+    }
+    false
+}
+
+/// Download a backup from its manifest message into `path`, checking the
+/// manifest signature before downloading anything else. Without a verify-key
+/// this fails unless `no_verify` is set.
+fn fetch<L: Logger>(
+    config: &Config,
+    id: NonZeroU64,
+    path: &Path,
+    no_verify: bool,
+    log: &mut L,
+) -> Result<(), String> {
+    let webhook = Webhook::new(
+        config
+            .webhook_source
+            .resolve()
+            .map_err(|why| format!("Failed to resolve webhook: {why}"))?,
+        config
+            .http
+            .client()
+            .map_err(|why| format!("Failed to set up http client: {why}"))?,
+    );
+
+    let attachments = webhook
+        .attachments(id, log)
+        .map_err(|why| format!("Failed to fetch manifest message: {why}"))?;
+    let attachment = |name: &str, log: &mut L| {
+        attachments.iter().find(|(x, _)| x == name).map(|(_, url)| {
+            webhook
+                .download(url, log)
+                .map_err(|why| format!("Failed to download {name}: {why}"))
+        })
+    };
+
+    let Some(data) = attachment("manifest.json", log) else {
+        return Err(format!("Message {id} is not a backup manifest"));
+    };
+    let data = data?;
+
+    match (&config.verify_key, attachment("manifest.sig", log)) {
+        (Some(key), Some(signature)) => {
+            if !manifest::verify(key, &data, &signature?) {
+                return Err("Manifest signature is invalid, refusing to restore".into());
+            }
+            log.info("Manifest signature is valid");
+        }
+        (Some(_), None) => return Err("Manifest is not signed, refusing to restore".into()),
+        (None, _) if no_verify => {
+            log.warn("No verify-key is set, restoring without checking the manifest")
+        }
+        (None, _) => {
+            return Err(
+                "No verify-key is set, refusing to restore an unverified manifest. Set verify-key or pass --no-verify".into(),
+            );
+        }
+    }
+
+    let manifest = Manifest::parse(&data)?;
+    log.info(&format!(
+        "Downloading {} chunks of {}",
+        manifest.chunks.len(),
+        manifest.job
+    ));
+
+    let mut file =
+        temp::create_file(path).map_err(|why| format!("Failed to create temporary file: {why}"))?;
+    for (i, (id, hash)) in manifest.chunks.iter().enumerate() {
+        let attachments = webhook
+            .attachments(*id, log)
+            .map_err(|why| format!("Failed to fetch chunk message {id}: {why}"))?;
+        let Some((_, url)) = attachments.first() else {
+            return Err(format!("Chunk message {id} has no attachments"));
+        };
+        let data = webhook
+            .download(url, log)
+            .map_err(|why| format!("Failed to download chunk {i}: {why}"))?;
+        if manifest::sha256(&data) != *hash {
+            return Err(format!("Chunk {i} does not match the manifest"));
+        }
+        file.write_all(&data)
+            .map_err(|why| format!("Failed to write archive: {why}"))?;
+        log.info(&format!(
+            "Downloaded chunk {}/{}",
+            i + 1,
+            manifest.chunks.len()
+        ));
+    }
+
+    Ok(())
+}
+
+fn read_magic(file: &mut File) -> Result<Vec<u8>, String> {
+    let mut magic = vec![];
+    file.take(crypt::MAGIC.len() as u64)
+        .read_to_end(&mut magic)
+        .and_then(|_| file.rewind())
+        .map_err(|why| format!("Failed to read archive: {why}"))?;
+    Ok(magic)
+}
+
+/// Extract a backup archive into `destination`, restoring file metadata.
+pub fn restore<L: Logger>(
+    config: &Config,
+    archive: &Path,
+    destination: &Path,
+    identity: Option<&Path>,
+    no_verify: bool,
+    log: &mut L,
+) -> Result<(), String> {
+    let fetched = Defer::new(temp_path(), |x| {
+        let _ = fs::remove_file(x);
+    });
+    let archive = match archive.to_str().and_then(|x| x.parse().ok()) {
+        Some(id) if !archive.exists() => {
+            fetch(config, id, &fetched, no_verify, log)?;
+            &*fetched
+        }
+        _ => archive,
+    };
+
+    let mut file = File::open(archive).map_err(|why| format!("Failed to open archive: {why}"))?;
+    let mut magic = read_magic(&mut file)?;
+
+    let decrypted = Defer::new(temp_path(), |x| {
+        let _ = fs::remove_file(x);
+    });
+    if magic.starts_with(b"age-encr") {
+        let Some(identity) = identity else {
+            return Err("Archive is encrypted to age recipients, pass --identity <file>".into());
+        };
+
+        let identities = File::open(identity)
+            .map(BufReader::new)
+            .and_then(age::IdentityFile::from_buffer)
+            .map_err(|why| format!("Failed to read identity file: {why}"))?
+            .into_identities()
+            .map_err(|why| format!("Failed to read identity file: {why}"))?;
+
+        log.info("Decrypting archive...");
+        let mut output = temp::create_file(&decrypted)
+            .map_err(|why| format!("Failed to create temporary file: {why}"))?;
+        let mut reader = age::Decryptor::new_buffered(BufReader::new(file))
+            .and_then(|x| x.decrypt(identities.iter().map(|x| x.as_ref() as _)))
+            .map_err(|why| format!("Failed to decrypt archive: {why}"))?;
+        io::copy(&mut reader, &mut output)
+            .map_err(|why| format!("Failed to decrypt archive: {why}"))?;
+
+        file = File::open(&*decrypted)
+            .map_err(|why| format!("Failed to open decrypted archive: {why}"))?;
+        magic = read_magic(&mut file)?;
+    } else if magic.starts_with(crypt::MAGIC) {
+        let Some(password) = &config.password else {
+            return Err("Archive is encrypted, but no password is set".into());
+        };
+
+        log.info("Decrypting archive...");
+        let output = temp::create_file(&decrypted)
+            .map_err(|why| format!("Failed to create temporary file: {why}"))?;
+        crypt::decrypt(BufReader::new(file), BufWriter::new(output), password)?;
+
+        file = File::open(&*decrypted)
+            .map_err(|why| format!("Failed to open decrypted archive: {why}"))?;
+        magic = read_magic(&mut file)?;
+    }
+
+    let Some(format) = Format::detect(&magic) else {
+        return Err("Failed to read archive: unknown format".into());
+    };
+
+    fs::create_dir_all(destination)
+        .map_err(|why| format!("Failed to create {destination:?}: {why}"))?;
+
+    let error = |why: io::Error| format!("Failed to read archive: {why}");
+    match format {
+        Format::Zip => restore_zip(config, file, destination, log),
+        Format::TarZstd => restore_tar(zstd::Decoder::new(file).map_err(error)?, destination, log),
+        Format::TarXz => restore_tar(xz2::read::XzDecoder::new(file), destination, log),
+        Format::TarGzip => restore_tar(flate2::read::GzDecoder::new(file), destination, log),
+    }
+}
+
+fn restore_tar<L: Logger>(
+    reader: impl Read,
+    destination: &Path,
+    log: &mut L,
+) -> Result<(), String> {
+    let mut archive = tar::Archive::new(reader);
+    archive.set_preserve_permissions(true);
+    archive.set_preserve_mtime(true);
+    archive.set_overwrite(true);
+    // Only root can give files away
+    #[cfg(unix)]
+    archive.set_preserve_ownerships(unsafe { libc::geteuid() } == 0);
+
+    archive
+        .unpack(destination)
+        .map_err(|why| format!("Failed to extract archive: {why}"))?;
+
+    log.info(&format!("Restored archive into {destination:?}"));
+
+    Ok(())
+}
+
+fn restore_zip<L: Logger>(
+    config: &Config,
+    file: File,
+    destination: &Path,
+    log: &mut L,
+) -> Result<(), String> {
+    let mut zip = ZipArchive::new(file).map_err(|why| format!("Failed to read archive: {why}"))?;
+
+    // Extracting files changes directory mtimes, so those are set at the end
+    let mut dirs: Vec<(PathBuf, Attributes)> = vec![];
+
+    for i in 0..zip.len() {
+        let mut entry = match &config.password {
+            Some(x) => zip.by_index_decrypt(i, x.as_bytes()),
+            None => zip.by_index(i),
+        }
+        .map_err(|why| format!("Failed to read archive entry: {why}"))?;
+
+        let Some(name) = entry.enclosed_name() else {
+            log.warn(&format!("Skipped {}: unsafe path", entry.name()));
+            continue;
+        };
+        if through_symlink(destination, &name) {
+            log.warn(&format!(
+                "Skipped {}: path leads through a symlink",
+                entry.name()
+            ));
+            continue;
+        }
+
+        let path = destination.join(&name);
+        let attributes = Attributes::new(&entry);
+        let error = |why: io::Error| format!("Failed to extract {path:?}: {why}");
+
+        if entry.is_dir() {
+            fs::create_dir_all(&path).map_err(error)?;
+            dirs.push((path, attributes));
+            continue;
+        }
+
+        if let Some(x) = path.parent() {
+            fs::create_dir_all(x).map_err(error)?;
+        }
+        if fs::symlink_metadata(&path).is_ok_and(|x| !x.is_dir()) {
+            fs::remove_file(&path).map_err(error)?;
+        }
+
+        if entry.is_symlink() {
+            let mut target = String::new();
+            entry.read_to_string(&mut target).map_err(error)?;
+
+            #[cfg(unix)]
+            {
+                std::os::unix::fs::symlink(&target, &path).map_err(error)?;
+                attributes.apply(&path, true, log);
+                log.info(&format!("Restored symlink {}", entry.name()));
+            }
+            #[cfg(windows)]
+            log.warn(&format!("Skipped symlink {}", entry.name()));
+
+            continue;
+        }
+
+        let mut file = File::create(&path).map_err(error)?;
+        io::copy(&mut entry, &mut file).map_err(error)?;
+        drop(file);
+        attributes.apply(&path, false, log);
+
+        log.info(&format!("Restored file {}", entry.name()));
+    }
+
+    dirs.sort_by_key(|(x, _)| std::cmp::Reverse(x.components().count()));
+    for (path, attributes) in dirs {
+        attributes.apply(&path, false, log);
+    }
+
+    Ok(())
+}
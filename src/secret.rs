@@ -0,0 +1,149 @@
+use std::{env, fs, path::PathBuf, process::Command};
+
+/// A config value that can be read from somewhere else instead of being
+/// written into the config, by adding a suffix to its directive.
+///
+/// - `-env NAME` reads an environment variable
+/// - `-file /path` reads a file, without the trailing newline
+/// - `-command command` runs a shell command and uses its output
+#[derive(Clone)]
+pub enum Secret {
+    Plain(String),
+    Env(String),
+    File(PathBuf),
+    Command(String),
+}
+impl std::fmt::Debug for Secret {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Plain(_) => f.write_str("Plain(***)"),
+            _ => f.write_str(&self.name()),
+        }
+    }
+}
+impl Secret {
+    /// Parse the config line `line` if it sets the `name` directive, the
+    /// value of `{name} value` is always taken as is.
+    pub fn parse(line: &str, name: &str) -> Option<Self> {
+        let (directive, value) = line.split_once(' ')?;
+        let value = value.trim();
+        Some(match directive.strip_prefix(name)? {
+            "" => Self::Plain(value.to_string()),
+            "-env" => Self::Env(value.to_string()),
+            "-file" => Self::File(PathBuf::from(value)),
+            "-command" => Self::Command(value.to_string()),
+            _ => return None,
+        })
+    }
+
+    pub fn resolve(&self) -> Result<String, String> {
+        let value = match self {
+            Self::Plain(x) => return Ok(x.clone()),
+            Self::Env(x) => env::var(x).map_err(|why| format!("env {x}: {why}"))?,
+            Self::File(x) => fs::read_to_string(x).map_err(|why| format!("file {x:?}: {why}"))?,
+            Self::Command(x) => {
+                #[cfg(unix)]
+                let output = Command::new("sh").arg("-c").arg(x).output();
+                #[cfg(windows)]
+                let output = Command::new("cmd").arg("/C").arg(x).output();
+
+                let output = output.map_err(|why| format!("command {x}: {why}"))?;
+                if !output.status.success() {
+                    let stderr = String::from_utf8_lossy(&output.stderr);
+                    return Err(if stderr.trim().is_empty() {
+                        format!("command {x}: exited with {}", output.status)
+                    } else {
+                        format!(
+                            "command {x}: exited with {}\n\n{}",
+                            output.status,
+                            stderr.trim()
+                        )
+                    });
+                }
+                String::from_utf8(output.stdout)
+                    .map_err(|_| format!("command {x}: output is not valid UTF-8"))?
+            }
+        };
+
+        let value = value.trim_end_matches(['\r', '\n']);
+        if value.is_empty() {
+            return Err(format!("{}: value is empty", self.name()));
+        }
+        Ok(value.to_string())
+    }
+
+    /// Where the value comes from, without the value itself.
+    fn name(&self) -> String {
+        match self {
+            Self::Plain(_) => "value".into(),
+            Self::Env(x) => format!("env {x}"),
+            Self::File(x) => format!("file {x:?}"),
+            Self::Command(x) => format!("command {x}"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use super::*;
+
+    #[test]
+    fn directives_pick_the_source() {
+        assert!(matches!(
+            Secret::parse("password hunter2", "password"),
+            Some(Secret::Plain(x)) if x == "hunter2"
+        ));
+        assert!(matches!(
+            Secret::parse("password-env  BACKUP_PASSWORD ", "password"),
+            Some(Secret::Env(x)) if x == "BACKUP_PASSWORD"
+        ));
+        assert!(matches!(
+            Secret::parse("password-file /run/secrets/backup", "password"),
+            Some(Secret::File(x)) if x == Path::new("/run/secrets/backup")
+        ));
+        assert!(matches!(
+            Secret::parse("password-command pass show backup", "password"),
+            Some(Secret::Command(x)) if x == "pass show backup"
+        ));
+        assert!(Secret::parse("password-url x", "password").is_none());
+        assert!(Secret::parse("webhook x", "password").is_none());
+    }
+
+    #[test]
+    fn plain_values_are_never_interpreted() {
+        for value in ["cmd:touch /tmp/pwned", "env:HOME", "file:/etc/passwd"] {
+            let secret = Secret::parse(&format!("password {value}"), "password").unwrap();
+            assert_eq!(secret.resolve().unwrap(), value);
+        }
+    }
+
+    #[test]
+    fn values_are_resolved() {
+        let path = std::env::temp_dir().join(format!("dbu-secret-test-{}", std::process::id()));
+        fs::write(&path, "from a file\n").unwrap();
+        assert_eq!(Secret::File(path.clone()).resolve().unwrap(), "from a file");
+        fs::remove_file(&path).unwrap();
+        assert!(Secret::File(path).resolve().is_err());
+
+        let expected = env::var("PATH").unwrap();
+        assert_eq!(Secret::Env("PATH".into()).resolve().unwrap(), expected);
+        assert!(Secret::Env("DBU_SURELY_UNSET".into()).resolve().is_err());
+
+        assert_eq!(
+            Secret::Command("echo from a command".into())
+                .resolve()
+                .unwrap(),
+            "from a command"
+        );
+        assert!(Secret::Command("exit 1".into()).resolve().is_err());
+        assert!(Secret::Command("true".into()).resolve().is_err());
+    }
+
+    #[test]
+    fn values_stay_out_of_debug_output() {
+        let secret = Secret::parse("password hunter2", "password").unwrap();
+        assert!(!format!("{secret:?}").contains("hunter2"));
+    }
+}
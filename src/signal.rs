@@ -0,0 +1,96 @@
+//! Signal handling. SIGTERM and SIGINT stop the current backup at the next
+//! safe point, a second one exits right away. SIGHUP reloads the config and
+//! SIGUSR1 starts a backup immediately. The control socket can cancel a
+//! backup or start one the same way.
+
+use std::{
+    sync::atomic::{AtomicBool, Ordering},
+    time::{Duration, Instant},
+};
+
+static STOP: AtomicBool = AtomicBool::new(false);
+static RELOAD: AtomicBool = AtomicBool::new(false);
+static TRIGGER: AtomicBool = AtomicBool::new(false);
+static CANCEL: AtomicBool = AtomicBool::new(false);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Event {
+    Stop,
+    Reload,
+    Trigger,
+}
+
+#[cfg(unix)]
+extern "C" fn handle(signal: libc::c_int) {
+    match signal {
+        // Exit right away on the second one
+        libc::SIGTERM | libc::SIGINT if STOP.swap(true, Ordering::SeqCst) => unsafe {
+            libc::_exit(128 + signal)
+        },
+        libc::SIGHUP => RELOAD.store(true, Ordering::SeqCst),
+        libc::SIGUSR1 => TRIGGER.store(true, Ordering::SeqCst),
+        _ => (),
+    }
+}
+
+#[cfg(unix)]
+pub fn install() {
+    for x in [libc::SIGTERM, libc::SIGINT, libc::SIGHUP, libc::SIGUSR1] {
+        unsafe {
+            let mut action: libc::sigaction = std::mem::zeroed();
+            action.sa_sigaction = handle as extern "C" fn(libc::c_int) as libc::sighandler_t;
+            action.sa_flags = libc::SA_RESTART;
+            libc::sigemptyset(&mut action.sa_mask);
+            libc::sigaction(x, &action, std::ptr::null_mut());
+        }
+    }
+}
+
+#[cfg(windows)]
+pub fn install() {}
+
+/// Whether we were asked to shut down.
+pub fn exiting() -> bool {
+    STOP.load(Ordering::SeqCst)
+}
+
+/// Whether the current backup should stop, because we're shutting down or it
+/// was cancelled.
+pub fn stopping() -> bool {
+    exiting() || CANCEL.load(Ordering::SeqCst)
+}
+
+/// Stop the current backup without exiting.
+pub fn cancel() {
+    CANCEL.store(true, Ordering::SeqCst);
+}
+
+/// Start a backup right away, like SIGUSR1.
+pub fn trigger() {
+    TRIGGER.store(true, Ordering::SeqCst);
+}
+
+/// Sleep for `duration`, returning early if a signal arrives.
+pub fn wait(duration: Duration) -> Option<Event> {
+    // Too late to cancel a backup that has already finished
+    CANCEL.store(false, Ordering::SeqCst);
+
+    let deadline = Instant::now() + duration;
+    loop {
+        if exiting() {
+            return Some(Event::Stop);
+        }
+        if RELOAD.swap(false, Ordering::SeqCst) {
+            return Some(Event::Reload);
+        }
+        if TRIGGER.swap(false, Ordering::SeqCst) {
+            return Some(Event::Trigger);
+        }
+
+        let now = Instant::now();
+        if now >= deadline {
+            return None;
+        }
+        std::thread::sleep((deadline - now).min(Duration::from_millis(200)));
+    }
+}
@@ -0,0 +1,101 @@
+//! What carries over between runs of a job, so `--once` runs and restarts
+//! continue the run count and remember the last success. Kept in the state
+//! file set by `state-file`, in systemd's `StateDirectory=` or in the user's
+//! state dir.
+
+use std::{
+    fs::{self, DirBuilder},
+    io::{self, Write},
+    path::PathBuf,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use crate::{config::Config, temp};
+
+pub struct State {
+    path: Option<PathBuf>,
+    /// Runs of the job so far.
+    pub runs: u64,
+    pub last_success: Option<SystemTime>,
+}
+impl State {
+    /// State of the job, empty if there is none yet.
+    pub fn load(path: Option<PathBuf>) -> Self {
+        let mut state = Self {
+            path,
+            runs: 0,
+            last_success: None,
+        };
+        let Some(Ok(data)) = state.path.as_ref().map(fs::read_to_string) else {
+            return state;
+        };
+
+        for line in data.lines() {
+            match line.split_once(' ') {
+                Some(("runs", x)) => state.runs = x.parse().unwrap_or_default(),
+                Some(("last-success", x)) => {
+                    state.last_success = x.parse().ok().map(|x| UNIX_EPOCH + Duration::from_secs(x))
+                }
+                _ => (),
+            }
+        }
+        state
+    }
+
+    /// Write the state back, does nothing if it has nowhere to go.
+    pub fn save(&self) -> io::Result<()> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+
+        let mut data = format!("runs {}\n", self.runs);
+        if let Some(x) = self.last_success {
+            let x = x.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+            data += &format!("last-success {x}\n");
+        }
+
+        if let Some(dir) = path.parent() {
+            let mut builder = DirBuilder::new();
+            builder.recursive(true);
+            #[cfg(unix)]
+            std::os::unix::fs::DirBuilderExt::mode(&mut builder, 0o700);
+            builder.create(dir)?;
+        }
+
+        // Never leave a half-written file behind
+        let mut temp = path.as_os_str().to_owned();
+        temp.push(".tmp");
+        let temp = PathBuf::from(temp);
+        // Left over by a crash, creating refuses to reuse it
+        let _ = fs::remove_file(&temp);
+        temp::create_file(&temp)?.write_all(data.as_bytes())?;
+        fs::rename(&temp, path)
+    }
+}
+
+/// Where the state of the job goes, `None` if there is no place for it.
+pub fn path(config: &Config) -> Option<PathBuf> {
+    if let Some(x) = &config.state_file {
+        return Some(x.clone());
+    }
+
+    let dir = match std::env::var_os("STATE_DIRECTORY") {
+        // Set by systemd for StateDirectory=, possibly listing several
+        Some(x) => std::env::split_paths(&x).next()?,
+        None => user_dir()?.join("discord-backup-util"),
+    };
+    Some(dir.join(temp::job_file_name(&config.lock_key, "state")))
+}
+
+#[cfg(unix)]
+fn user_dir() -> Option<PathBuf> {
+    match std::env::var_os("XDG_STATE_HOME") {
+        Some(x) if !x.is_empty() => Some(PathBuf::from(x)),
+        _ => Some(PathBuf::from(std::env::var_os("HOME")?).join(".local/state")),
+    }
+}
+
+#[cfg(windows)]
+fn user_dir() -> Option<PathBuf> {
+    std::env::var_os("LOCALAPPDATA").map(PathBuf::from)
+}
@@ -1,34 +1,216 @@
-use std::{path::PathBuf, str::FromStr};
+use std::{
+    fs::{self, File},
+    io,
+    path::{Path, PathBuf},
+    sync::OnceLock,
+    time::{Duration, SystemTime},
+};
 
 use rand::Rng;
 
+use crate::log::Logger;
+
+const PREFIX: &str = "discord-backup-util.";
+
+/// Leftovers without a process id are removed once they are this old.
+const STALE_AGE: Duration = Duration::from_secs(24 * 60 * 60);
+
+static ROOT: OnceLock<PathBuf> = OnceLock::new();
+
+/// Put temporary files into `path` instead of the system temp dir.
+pub fn set_root(path: PathBuf) {
+    let _ = ROOT.set(path);
+}
+
+/// Dir temporary files go into.
 #[cfg(windows)]
-pub fn temp_path() -> PathBuf {
+pub fn root() -> PathBuf {
+    if let Some(x) = ROOT.get() {
+        return x.clone();
+    }
+
     let Ok(root) = std::env::var("TEMP") else {
         panic!("'TEMP' is not set");
     };
+    PathBuf::from(root)
+}
+
+/// Dir temporary files go into.
+#[cfg(unix)]
+pub fn root() -> PathBuf {
+    if let Some(x) = ROOT.get() {
+        return x.clone();
+    }
+
+    match std::env::var("TMPDIR") {
+        Ok(x) => PathBuf::from(x),
+        Err(_) => PathBuf::from("/var/tmp"),
+    }
+}
+
+/// Name of a file that belongs to the job identified by `key`.
+pub fn job_file_name(key: &str, extension: &str) -> String {
+    let name: String = key
+        .chars()
+        .map(|x| {
+            if x.is_ascii_alphanumeric() || x == '-' || x == '_' {
+                x
+            } else {
+                '_'
+            }
+        })
+        .collect();
+    format!("discord-backup-util-{name}.{extension}")
+}
+
+/// Dir for files kept while jobs run, like locks, that nobody else can put
+/// files into. Names in a shared temp dir are predictable, another user could
+/// plant a symlink there first.
+pub fn runtime_dir() -> io::Result<PathBuf> {
+    // Set by systemd for RuntimeDirectory=, possibly listing several
+    if let Some(x) = std::env::var_os("RUNTIME_DIRECTORY") {
+        if let Some(x) = std::env::split_paths(&x).next() {
+            return Ok(x);
+        }
+    }
+
+    #[cfg(windows)]
+    return Ok(root());
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::MetadataExt;
+
+        let uid = unsafe { libc::geteuid() };
+        let path = root().join(format!("discord-backup-util-{uid}"));
+        match create_dir(&path) {
+            Err(why) if why.kind() != io::ErrorKind::AlreadyExists => return Err(why),
+            _ => (),
+        }
+
+        let meta = fs::symlink_metadata(&path)?;
+        if !meta.is_dir() || meta.uid() != uid || meta.mode() & 0o077 != 0 {
+            return Err(io::Error::other(format!(
+                "{path:?} is not a directory only we can access"
+            )));
+        }
+        Ok(path)
+    }
+}
+
+/// Path of a file in [runtime_dir] that belongs to the job identified by
+/// `key`, kept across runs.
+pub fn job_file(key: &str, extension: &str) -> io::Result<PathBuf> {
+    Ok(runtime_dir()?.join(job_file_name(key, extension)))
+}
 
+/// A new unique path in the temp dir, tagged with our process id so
+/// leftovers of crashed runs can be told apart.
+pub fn temp_path() -> PathBuf {
     let name: String = rand::thread_rng()
         .sample_iter(rand::distributions::Alphanumeric)
         .map(|x| x as char)
         .take(32)
         .collect();
 
-    PathBuf::from_str(&format!("{root}\\discord-backup-util.{name}")).unwrap()
+    root().join(format!("{PREFIX}{}.{name}", std::process::id()))
+}
+
+/// Create a temp file only we can read, failing if it already exists.
+pub fn create_file(path: &Path) -> io::Result<File> {
+    let mut options = File::options();
+    options.read(true).write(true).create_new(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    options.open(path)
 }
 
+/// Create a temp dir only we can access, failing if it already exists.
+pub fn create_dir(path: &Path) -> io::Result<()> {
+    let mut builder = fs::DirBuilder::new();
+    #[cfg(unix)]
+    std::os::unix::fs::DirBuilderExt::mode(&mut builder, 0o700);
+    builder.create(path)
+}
+
+/// Free space in the temp dir, if it can be told.
 #[cfg(unix)]
-pub fn temp_path() -> PathBuf {
-    let temp = match std::env::var("TMPDIR") {
-        Ok(x) => x,
-        Err(_) => "/var/tmp".to_string(),
+pub fn available_space() -> Option<u64> {
+    use std::os::unix::ffi::OsStrExt;
+
+    let path = std::ffi::CString::new(root().as_os_str().as_bytes()).ok()?;
+    let mut stat = std::mem::MaybeUninit::<libc::statvfs>::uninit();
+    if unsafe { libc::statvfs(path.as_ptr(), stat.as_mut_ptr()) } != 0 {
+        return None;
+    }
+    let stat = unsafe { stat.assume_init() };
+    // Field types differ between platforms
+    #[allow(clippy::unnecessary_cast)]
+    Some(stat.f_bavail as u64 * stat.f_frsize as u64)
+}
+
+#[cfg(windows)]
+pub fn available_space() -> Option<u64> {
+    None
+}
+
+/// Whether a leftover named `name` belongs to a process that is gone.
+fn is_stale(name: &str, path: &Path) -> bool {
+    let Some(rest) = name.strip_prefix(PREFIX) else {
+        return false;
     };
 
-    let name: String = rand::thread_rng()
-        .sample_iter(rand::distributions::Alphanumeric)
-        .map(|x| x as char)
-        .take(32)
-        .collect();
+    #[cfg(unix)]
+    if let Some(pid) = rest
+        .split_once('.')
+        .and_then(|(x, _)| x.parse::<i32>().ok())
+    {
+        if pid as u32 == std::process::id() {
+            return false;
+        }
+        let alive = unsafe { libc::kill(pid, 0) } == 0
+            || io::Error::last_os_error().raw_os_error() != Some(libc::ESRCH);
+        return !alive;
+    }
+
+    fs::symlink_metadata(path)
+        .and_then(|x| x.modified())
+        .ok()
+        .and_then(|x| SystemTime::now().duration_since(x).ok())
+        .is_some_and(|x| x > STALE_AGE)
+}
+
+/// Remove temp files and dirs left behind by earlier runs that crashed.
+pub fn cleanup<L: Logger>(log: &mut L) {
+    let Ok(entries) = fs::read_dir(root()) else {
+        return;
+    };
+
+    for x in entries.flatten() {
+        let name = x.file_name().to_string_lossy().into_owned();
+        let path = x.path();
+        if !is_stale(&name, &path) {
+            continue;
+        }
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::MetadataExt;
+
+            // Shared temp dirs may hold leftovers of other users
+            if fs::symlink_metadata(&path).map(|x| x.uid()).ok() != Some(unsafe { libc::geteuid() })
+            {
+                continue;
+            }
+        }
 
-    PathBuf::from_str(&format!("{temp}/discord-backup-util.{name}")).unwrap()
+        let result = match x.file_type() {
+            Ok(x) if x.is_dir() => fs::remove_dir_all(&path),
+            _ => fs::remove_file(&path),
+        };
+        match result {
+            Ok(()) => log.info(&format!("Removed stale temporary file {path:?}")),
+            Err(why) => log.warn(&format!("Failed to remove stale {path:?}: {why}")),
+        }
+    }
 }
@@ -0,0 +1,177 @@
+//! Upload bandwidth limiting. All requests share one token bucket, so
+//! parallel uploads split the limit between them.
+
+use std::{
+    io::Read,
+    sync::Mutex,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use crate::time;
+
+/// Time of day range, in minutes since local midnight. May wrap around
+/// midnight, e.g. `22:00-06:00`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Window(u32, u32);
+impl Window {
+    pub fn parse(value: &str) -> Option<Self> {
+        let minutes = |x: &str| {
+            let (hour, minute) = x.trim().split_once(':')?;
+            let (hour, minute): (u32, u32) = (hour.parse().ok()?, minute.parse().ok()?);
+            (hour < 24 && minute < 60).then_some(hour * 60 + minute)
+        };
+        let (start, end) = value.split_once('-')?;
+        Some(Self(minutes(start)?, minutes(end)?))
+    }
+
+    fn contains(&self, minute: u32) -> bool {
+        if self.0 <= self.1 {
+            (self.0..self.1).contains(&minute)
+        } else {
+            minute >= self.0 || minute < self.1
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Limit {
+    /// Bytes per second.
+    pub rate: u64,
+    /// When the limit applies, always if unset.
+    pub window: Option<Window>,
+}
+
+#[derive(Debug)]
+struct Bucket {
+    /// Bytes that can be sent right away, negative if requests are waiting.
+    tokens: f64,
+    last: Instant,
+}
+
+#[derive(Debug)]
+pub struct Throttle {
+    limits: Vec<Limit>,
+    bucket: Mutex<Bucket>,
+}
+impl Throttle {
+    pub fn new(limits: Vec<Limit>) -> Self {
+        Self {
+            limits,
+            bucket: Mutex::new(Bucket {
+                tokens: 0.0,
+                last: Instant::now(),
+            }),
+        }
+    }
+
+    /// Limit in effect right now, later limits take precedence.
+    fn rate(&self) -> Option<u64> {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|x| x.as_secs() as i64)
+            .unwrap_or_default();
+        let (_, _, _, hour, minute, _) = time::civil(now + time::local_offset(now));
+
+        self.limits
+            .iter()
+            .rev()
+            .find(|x| x.window.is_none_or(|x| x.contains(hour * 60 + minute)))
+            .map(|x| x.rate)
+    }
+
+    /// Wait until `bytes` more may be sent.
+    pub fn take(&self, bytes: usize) {
+        let Some(rate) = self.rate().map(|x| x as f64) else {
+            return;
+        };
+
+        let wait = {
+            let mut bucket = self.bucket.lock().unwrap();
+            let now = Instant::now();
+            // Allow bursts of up to a second worth of data
+            bucket.tokens =
+                (bucket.tokens + now.duration_since(bucket.last).as_secs_f64() * rate).min(rate);
+            bucket.last = now;
+            bucket.tokens -= bytes as f64;
+            -bucket.tokens / rate
+        };
+
+        if wait > 0.0 {
+            std::thread::sleep(Duration::from_secs_f64(wait));
+        }
+    }
+}
+
+/// Reads from `R` no faster than the throttle allows.
+pub struct Throttled<'a, R: Read> {
+    inner: R,
+    throttle: &'a Throttle,
+}
+impl<'a, R: Read> Throttled<'a, R> {
+    pub fn new(inner: R, throttle: &'a Throttle) -> Self {
+        Self { inner, throttle }
+    }
+}
+impl<R: Read> Read for Throttled<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        // Small reads keep the rate smooth
+        let len = buf.len().min(16 * 1024);
+        let read = self.inner.read(&mut buf[..len])?;
+        self.throttle.take(read);
+        Ok(read)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn windows() {
+        let office = Window::parse("08:00-18:30").unwrap();
+        assert!(office.contains(8 * 60));
+        assert!(office.contains(18 * 60 + 29));
+        assert!(!office.contains(18 * 60 + 30));
+        assert!(!office.contains(7 * 60));
+
+        let night = Window::parse("22:00-06:00").unwrap();
+        assert!(night.contains(23 * 60));
+        assert!(night.contains(60));
+        assert!(!night.contains(12 * 60));
+
+        assert_eq!(Window::parse("24:00-06:00"), None);
+        assert_eq!(Window::parse("8-18"), None);
+    }
+
+    #[test]
+    fn limits_rate() {
+        let throttle = Throttle::new(vec![Limit {
+            rate: 100_000,
+            window: None,
+        }]);
+
+        let started = Instant::now();
+        let mut data = Throttled::new(&[0u8; 250_000][..], &throttle);
+        std::io::copy(&mut data, &mut std::io::sink()).unwrap();
+        let elapsed = started.elapsed().as_secs_f64();
+        // Only a lower bound, loaded machines can be arbitrarily slow
+        assert!(elapsed >= 2.0, "{elapsed}");
+    }
+
+    #[test]
+    fn unlimited_outside_window() {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs() as i64;
+        let (_, _, _, hour, _, _) = time::civil(now + time::local_offset(now));
+        let later = (hour + 2) % 24;
+
+        let throttle = Throttle::new(vec![Limit {
+            rate: 1,
+            window: Window::parse(&format!("{later:02}:00-{:02}:00", (later + 1) % 24)),
+        }]);
+        assert_eq!(throttle.rate(), None);
+        throttle.take(1_000_000);
+    }
+}
@@ -0,0 +1,55 @@
+//! Calendar conversions for unix timestamps, all in UTC unless noted.
+
+/// Split a unix timestamp into `(year, month, day, hour, minute, second)`.
+pub fn civil(time: i64) -> (i64, u32, u32, u32, u32, u32) {
+    let days = time.div_euclid(86400);
+    let secs = time.rem_euclid(86400) as u32;
+
+    // http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + i64::from(month <= 2);
+
+    (year, month, day, secs / 3600, secs / 60 % 60, secs % 60)
+}
+
+/// Inverse of [civil].
+pub fn unix(year: i64, month: u32, day: u32, hour: u32, minute: u32, second: u32) -> i64 {
+    // http://howardhinnant.github.io/date_algorithms.html#days_from_civil
+    let year = year - i64::from(month <= 2);
+    let era = year.div_euclid(400);
+    let yoe = year.rem_euclid(400);
+    let mp = (i64::from(month) + 9) % 12;
+    let doy = (153 * mp + 2) / 5 + i64::from(day) - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    let days = era * 146097 + doe - 719468;
+
+    days * 86400 + i64::from(hour) * 3600 + i64::from(minute) * 60 + i64::from(second)
+}
+
+/// Seconds local time is ahead of UTC at `time`.
+#[cfg(unix)]
+pub fn local_offset(time: i64) -> i64 {
+    let time = time as libc::time_t;
+    let mut tm = std::mem::MaybeUninit::<libc::tm>::uninit();
+    if unsafe { libc::localtime_r(&time, tm.as_mut_ptr()) }.is_null() {
+        return 0;
+    }
+    // Field types differ between platforms
+    #[allow(clippy::unnecessary_cast)]
+    let offset = unsafe { tm.assume_init() }.tm_gmtoff as i64;
+    offset
+}
+
+/// Local time isn't looked up on Windows, so timestamps are shown in UTC and
+/// time ranges that depend on it are rejected by the config.
+#[cfg(windows)]
+pub fn local_offset(_: i64) -> i64 {
+    0
+}
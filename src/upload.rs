@@ -1,10 +1,18 @@
 use std::{
-    fs::{self, File},
-    io::{Read, Write},
-    path::PathBuf,
-    process::{Command, Stdio},
+    borrow::Cow,
+    collections::VecDeque,
+    fs::{self, File, Metadata},
+    io::{ErrorKind, Read, Write},
+    num::NonZeroU64,
+    path::{Path, PathBuf},
+    process::{Command, ExitStatus, Stdio},
     rc::Rc,
-    sync::{atomic::AtomicU64, Mutex},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    thread::JoinHandle,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
 
 #[cfg(unix)]
@@ -12,22 +20,103 @@ use std::os::unix::fs::MetadataExt;
 #[cfg(windows)]
 use std::os::windows::fs::MetadataExt;
 
-use zip::{write::FileOptions, ZipWriter};
-
 use crate::{
-    config::Config,
+    archive::{self, ArchiveWriter},
+    config::{read_env_file, Config, Script},
+    control,
+    crypt::{self, Encryption, Encryptor},
+    glob::Ignore,
+    healthcheck,
     hook::{Message, Webhook},
-    log::Logger,
-    temp::temp_path,
+    log::{Logger, NullLogger},
+    manifest::{self, Manifest},
+    metrics, notify, signal,
+    temp::{self, temp_path},
     Defer,
 };
 
+/// Head message status when a signal stopped the backup.
+const INTERRUPTED: &str = "Backup interrupted";
+
+/// How long to keep reading script output after the script exited, for
+/// background processes it started that still hold stdout or stderr open.
+const OUTPUT_GRACE: Duration = Duration::from_secs(1);
+
+/// Start of a download script too big for one message, which collects its
+/// parts into a temporary file, followed by `;dl <message id>` for each part.
+const OVERFLOW_HEAD: &str = r#"TFILE=$(mktemp);dl(){ curl -f -L "$(curl -f -L "$DBU_WEBHOOK/messages/$1"|grep -Eo '"url":"[^"]+"'|grep -Eo 'https[^"]+')">>"$TFILE";if [ ! $? -eq 0 ];then sleep 5;dl "$1";fi };printf "">"$TFILE""#;
+/// Runs and removes the collected script.
+const OVERFLOW_TAIL: &str = r#";sh "$TFILE";rm "$TFILE""#;
+
+/// Output captured from the backup script.
+///
+/// Only the last `limit` bytes are kept in memory, the full output is
+/// additionally written to `file` if one is set.
+struct ScriptLog {
+    tail: VecDeque<u8>,
+    limit: usize,
+    file: Option<File>,
+}
+impl ScriptLog {
+    fn new(limit: usize) -> Self {
+        Self {
+            tail: VecDeque::new(),
+            limit,
+            file: None,
+        }
+    }
+
+    fn push(&mut self, bytes: &[u8]) {
+        if let Some(file) = &mut self.file {
+            if file.write_all(bytes).is_err() {
+                self.file = None;
+            }
+        }
+
+        let bytes = &bytes[bytes.len().saturating_sub(self.limit)..];
+        let overflow = (self.tail.len() + bytes.len()).saturating_sub(self.limit);
+        self.tail.drain(..overflow);
+        self.tail.extend(bytes);
+    }
+
+    fn tail(&self) -> String {
+        String::from_utf8_lossy(&self.tail.iter().copied().collect::<Vec<u8>>()).into_owned()
+    }
+}
+
+/// Copy everything from `from` into `to` while also recording it in `log`,
+/// until `from` closes or `detached` is set.
+fn tee(
+    mut from: impl Read + Send + 'static,
+    mut to: impl Write + Send + 'static,
+    log: Arc<Mutex<ScriptLog>>,
+    detached: Arc<AtomicBool>,
+) -> JoinHandle<()> {
+    std::thread::spawn(move || {
+        let mut buffer = vec![0; 8192];
+        loop {
+            match from.read(&mut buffer) {
+                Ok(0) => break,
+                // The backup moved on, drop the pipe
+                Ok(_) if detached.load(Ordering::Relaxed) => break,
+                Ok(len) => {
+                    let _ = to.write_all(&buffer[0..len]);
+                    let _ = to.flush();
+                    log.lock().unwrap().push(&buffer[0..len]);
+                }
+                Err(why) if why.kind() == ErrorKind::Interrupted => (),
+                Err(_) => break,
+            }
+        }
+    })
+}
+
 fn upload_chunked(
     block_size: u8,
     webhook: &Webhook,
     mut file: impl Read,
     name: impl Fn(usize) -> String,
-    uploaded: impl Fn(Message, usize) -> std::io::Result<()>,
+    uploaded: impl Fn(Message, &[u8]) -> std::io::Result<()>,
     log: &mut impl Logger,
 ) -> std::io::Result<usize> {
     let chunk_size: usize = 1000 * 1000 * block_size as usize;
@@ -40,6 +129,8 @@ fn upload_chunked(
         let mut end = false;
 
         while ptr < chunk_size {
+            // Reading may encrypt the data as it goes
+            notify::watchdog();
             match file.read(&mut buffer[ptr..]) {
                 Ok(len) => {
                     ptr += len;
@@ -56,10 +147,10 @@ fn upload_chunked(
         }
 
         if ptr == chunk_size || end {
-            uploaded(
-                webhook.send(|x| x.file(name(i), buffer[0..ptr].to_vec()), log),
-                i,
-            )?;
+            let message = webhook
+                .try_send(|x| x.file(name(i), buffer[0..ptr].to_vec()), log)
+                .map_err(std::io::Error::other)?;
+            uploaded(message, &buffer[0..ptr])?;
             if end {
                 break Ok(i);
             }
@@ -69,183 +160,697 @@ fn upload_chunked(
     }
 }
 
-pub fn upload<'a, L: Logger>(config: &'a Config, log: &'a mut L) {
-    log.info("Trying to initiate a backup...");
-
-    let mut head = config
-        .webhook
-        .send(|x| x.content("Starting backup process..."), log);
+/// Adds files to the archive.
+struct Walker<'a, L: Logger> {
+    archive: &'a mut dyn ArchiveWriter,
+    max_file_size: Option<u64>,
+    /// Number of entries left out of the archive.
+    skipped: usize,
+    log: &'a mut L,
+}
+impl<L: Logger> Walker<'_, L> {
+    fn add_file(&mut self, path: &Path, name: &str, metadata: &Metadata) {
+        notify::watchdog();
+        if self.max_file_size.is_some_and(|x| metadata.len() > x) {
+            self.log.info(&format!("Skipped {name}: file is too large"));
+            self.skipped += 1;
+            return;
+        }
 
-    let dir = Defer::new(temp_path(), |x| fs::remove_dir_all(x));
-    if let Err(why) = fs::create_dir(&*dir) {
-        log.error(&format!("Failed to create dir: {why}"));
-        head.edit(&config.webhook, "Setup failed", log);
-        return;
-    }
-    let script = Defer::new(temp_path(), |x| fs::remove_file(x));
-    if let Err(why) = fs::write(&*script, &config.script) {
-        log.error(&format!("Failed to write script file: {why}"));
-        head.edit(&config.webhook, "Setup failed", log);
-        return;
-    }
+        let mut file = match File::open(path) {
+            Ok(x) => x,
+            Err(why) => {
+                self.log.warn(&format!("open() failed: {why}"));
+                return;
+            }
+        };
 
-    let mut iter = config.shell.iter();
-    let mut proc = match Command::new(iter.next().unwrap())
-        .args(iter)
-        .arg(&*script)
-        .stdout(Stdio::inherit())
-        .stderr(Stdio::inherit())
-        .current_dir(&*dir)
-        .spawn()
-    {
-        Ok(x) => x,
-        Err(why) => {
-            log.error(&format!("Failed to spawn child process: {why}"));
-            head.edit(&config.webhook, "Failed to start backup process", log);
+        if let Err(why) = self.archive.add_file(name, metadata, &mut file) {
+            self.log.warn(&format!("Failed to add file {name}: {why}"));
             return;
         }
-    };
 
-    head.edit(&config.webhook, "Backing up data...", log);
+        self.log.debug(&format!("Added file {name}"));
+    }
 
-    match proc.wait() {
-        Ok(x) => {
-            if !x.success() {
-                log.error("Backup process failed: exited with non-zero error code");
-                head.edit(&config.webhook, "Backup process failed", log);
+    /// Add a file, a symlink or a directory with everything inside it.
+    fn add(&mut self, path: PathBuf, name: String, metadata: &Metadata, ignore: &Ignore) {
+        let kind = metadata.file_type();
+
+        if kind.is_file() {
+            self.add_file(&path, &name, metadata);
+        } else if kind.is_symlink() {
+            let target = match fs::read_link(&path) {
+                Ok(x) => x,
+                Err(why) => {
+                    self.log.warn(&format!("readlink() failed: {why}"));
+                    return;
+                }
+            };
+            if let Err(why) = self.archive.add_symlink(&name, metadata, &target) {
+                self.log.warn(&format!("Failed to add symlink: {why}"));
                 return;
             }
-        }
-        Err(why) => {
-            log.error(&format!("Backup process failed: {why}"));
-            head.edit(&config.webhook, "Backup process failed", log);
-            return;
+            self.log.debug(&format!("Added symlink {name}"));
+        } else if kind.is_dir() {
+            if !name.is_empty() {
+                if let Err(why) = self.archive.add_directory(&name, metadata) {
+                    self.log.warn(&format!("Failed to add directory: {why}"));
+                    return;
+                }
+            }
+            self.walk(path, name, ignore);
+        } else {
+            self.log.warn(&format!(
+                "Skipped {name}: not a regular file, symlink or directory"
+            ));
+            self.skipped += 1;
         }
     }
 
-    let archive = Defer::new(temp_path(), |x| fs::remove_file(x));
-
-    let file = match File::create(&*archive) {
-        Ok(x) => x,
-        Err(why) => {
-            log.error(&format!("Failed to create temporary file: {why}"));
-            head.edit(&config.webhook, "Failed to start backup process", log);
-            return;
+    fn walk(&mut self, path: PathBuf, name: String, ignore: &Ignore) {
+        let mut ignore = Cow::Borrowed(ignore);
+        match fs::read_to_string(path.join(".backupignore")) {
+            Ok(x) => ignore.to_mut().extend(&name, &x),
+            Err(why) if why.kind() == ErrorKind::NotFound => (),
+            Err(why) => self
+                .log
+                .warn(&format!("Failed to read .backupignore: {why}")),
         }
-    };
-    let mut zip = ZipWriter::new(file);
 
-    log.info("Compressing the archive...");
-    head.edit(&config.webhook, "Compressing the archive...", log);
-
-    fn walk<L: Logger>(
-        path: PathBuf,
-        name: String,
-        zip: &mut ZipWriter<File>,
-        options: FileOptions<'_, ()>,
-        log: &mut L,
-    ) {
         for x in match fs::read_dir(path) {
             Ok(x) => x,
             Err(why) => {
-                log.warn(&format!("readdir() failed: {why}"));
+                self.log.warn(&format!("readdir() failed: {why}"));
                 return;
             }
         } {
+            if signal::stopping() {
+                return;
+            }
+
             let x = match x {
                 Ok(x) => x,
                 Err(why) => {
-                    log.warn(&format!("readdir() failed: {why}"));
+                    self.log.warn(&format!("readdir() failed: {why}"));
                     return;
                 }
             };
 
-            let metadata = match x.metadata() {
+            let metadata = match fs::symlink_metadata(x.path()) {
                 Ok(x) => x,
                 Err(why) => {
-                    log.warn(&format!("metadata() failed: {why}"));
+                    self.log.warn(&format!("metadata() failed: {why}"));
                     return;
                 }
             };
 
-            if metadata.is_file() {
-                if let Err(why) = zip.start_file(
-                    format!("{name}/{}", x.file_name().into_string().unwrap())
-                        .trim_start_matches('/'),
-                    options.large_file(metadata.len() >= 1024 * 1024 * 1024 * 4),
-                ) {
-                    log.warn(&format!("Failed to start zip header: {why}"));
-                    return;
-                }
+            let name = format!("{name}/{}", x.file_name().to_string_lossy())
+                .trim_start_matches('/')
+                .to_string();
 
-                let mut buffer = vec![0; 8192];
-                let mut file = match File::open(x.path()) {
-                    Ok(x) => x,
-                    Err(why) => {
-                        log.warn(&format!("open() failed: {why}"));
-                        return;
-                    }
-                };
-
-                loop {
-                    match file.read(&mut buffer) {
-                        Ok(x) => {
-                            if x == 0 {
-                                break;
-                            } else if let Err(why) = zip.write_all(&buffer[0..x]) {
-                                log.warn(&format!("write() failed: {why}"));
-                                return;
-                            }
-                        }
-                        Err(why) => {
-                            log.warn(&format!("read() failed: {why}"));
-                            return;
-                        }
-                    }
+            if ignore.is_ignored(&name, metadata.is_dir()) {
+                self.log.debug(&format!("Excluded {name}"));
+                self.skipped += 1;
+                continue;
+            }
+
+            self.add(x.path(), name, &metadata, &ignore);
+        }
+    }
+}
+
+impl<L: Logger> Walker<'_, L> {
+    /// Add the backup dir and all included paths.
+    fn add_all(&mut self, config: &Config, dir: &Path) -> Result<(), &'static str> {
+        self.walk(dir.to_path_buf(), String::new(), &config.exclude);
+
+        for (path, name) in &config.include {
+            let metadata = match fs::metadata(path) {
+                Ok(x) => x,
+                Err(why) => {
+                    self.log
+                        .error(&format!("Failed to read included path {path:?}: {why}"));
+                    return Err("Failed to read included path");
                 }
+            };
 
-                log.info(
-                    format!("Added file {name}/{}", x.file_name().into_string().unwrap())
-                        .trim_start_matches('/'),
-                );
+            self.add(path.clone(), name.clone(), &metadata, &config.exclude);
+        }
+
+        if signal::stopping() {
+            return Err(INTERRUPTED);
+        }
+        Ok(())
+    }
+}
+
+/// Adds up file sizes instead of archiving anything, to tell how large an
+/// archive can get.
+struct Estimate(u64);
+impl ArchiveWriter for Estimate {
+    fn add_file(&mut self, _: &str, metadata: &Metadata, _: &mut dyn Read) -> std::io::Result<()> {
+        self.0 += metadata.len();
+        Ok(())
+    }
+
+    fn add_symlink(&mut self, _: &str, _: &Metadata, _: &Path) -> std::io::Result<()> {
+        Ok(())
+    }
+
+    fn add_directory(&mut self, _: &str, _: &Metadata) -> std::io::Result<()> {
+        Ok(())
+    }
+
+    fn finish(self: Box<Self>) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+fn format_size(size: u64) -> String {
+    let volumes = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut volume = 0;
+    let mut size = size as f32;
+    while size >= 1024.0 && volume < volumes.len() - 1 {
+        size /= 1024.0;
+        volume += 1;
+    }
+    format!("{size:0.3}{}", volumes[volume])
+}
+
+/// Information about the current run, exposed to the backup script.
+pub struct Run {
+    /// Sequential number of this run of the job, starting at 1.
+    pub id: u64,
+    pub started_at: SystemTime,
+    pub last_success: Option<SystemTime>,
+}
+
+fn unix_time(time: SystemTime) -> String {
+    time.duration_since(UNIX_EPOCH)
+        .map(|x| x.as_secs())
+        .unwrap_or_default()
+        .to_string()
+}
+
+/// Edit the head message, mirroring it to the service status.
+fn set_status<L: Logger>(
+    config: &Config,
+    head: &mut Message,
+    text: impl Into<String>,
+    log: &mut L,
+) {
+    let text = text.into();
+    notify::status(&text);
+    control::phase(&text);
+    head.edit(&config.webhook, text, log);
+}
+
+/// Report that `done` out of `total` chunks are uploaded.
+fn chunk_progress(done: u64, total: u64) {
+    control::chunks(done, total);
+    if done < total {
+        notify::status(&format!("Uploading chunk {}/{total}", done + 1));
+    }
+}
+
+/// Update the metrics with the outcome of `run`.
+fn record<L: Logger>(
+    config: &Config,
+    run: &Run,
+    started: Instant,
+    result: Result<(u64, usize), &str>,
+    log: &mut L,
+) {
+    metrics::finished(run.started_at, started.elapsed(), result);
+    if let Some(path) = &config.metrics_file {
+        if let Err(why) = metrics::write(path) {
+            log.warn(&format!("Failed to write metrics to {path:?}: {why}"));
+        }
+    }
+}
+
+/// Run a backup, returns whether it succeeded.
+pub fn upload<'a, L: Logger>(config: &'a Config, run: &Run, log: &'a mut L) -> bool {
+    log.info("Trying to initiate a backup...");
+    config.healthchecks.ping(healthcheck::Event::Start, "", log);
+
+    notify::status("Starting backup process...");
+    control::started();
+    let started = Instant::now();
+    let Ok(mut head) = config
+        .webhook
+        .send(|x| x.content("Starting backup process..."), log)
+    else {
+        // Stopped before there was anything to report to
+        control::finished(false, INTERRUPTED);
+        record(config, run, started, Err("interrupted"), log);
+        config.healthchecks.ping(
+            healthcheck::Event::Failure,
+            &format!("{INTERRUPTED} (stage: interrupted)"),
+            log,
+        );
+        return false;
+    };
+
+    let mut env = vec![
+        ("BACKUP_JOB".to_string(), config.name.clone()),
+        ("BACKUP_RUN_ID".to_string(), run.id.to_string()),
+        ("BACKUP_STARTED_AT".to_string(), unix_time(run.started_at)),
+        (
+            "BACKUP_LAST_SUCCESS".to_string(),
+            run.last_success.map(unix_time).unwrap_or_default(),
+        ),
+    ];
+
+    let mut stage = Stage::Setup;
+    let result = match prepare(config, &mut env, &mut stage, log) {
+        Ok(()) => backup(config, &mut head, &env, &mut stage, log),
+        Err(x) => Err(x),
+    };
+
+    env.push((
+        "BACKUP_HEAD_MESSAGE_ID".to_string(),
+        head.id.unwrap().to_string(),
+    ));
+
+    match result {
+        Ok(report) => {
+            env.push(("BACKUP_OUTCOME".to_string(), "success".to_string()));
+            env.push((
+                "BACKUP_CHUNK_MESSAGE_IDS".to_string(),
+                report
+                    .chunks
+                    .iter()
+                    .map(|(x, _)| x.to_string())
+                    .collect::<Vec<String>>()
+                    .join(" "),
+            ));
+            if let Some((id, _)) = &report.script {
+                env.push(("BACKUP_SCRIPT_MESSAGE_ID".to_string(), id.to_string()));
+            }
+            env.push((
+                "BACKUP_MANIFEST_MESSAGE_ID".to_string(),
+                report.manifest.to_string(),
+            ));
+
+            if let Some(x) = &config.post_success {
+                run_hook(config, "post-success", x, &env, log);
+            }
+
+            control::finished(true, "Backup completed successfully");
+            config.healthchecks.ping(
+                healthcheck::Event::Success,
+                "Backup completed successfully",
+                log,
+            );
+            record(
+                config,
+                run,
+                started,
+                Ok((report.size, report.chunks.len())),
+                log,
+            );
+            true
+        }
+        Err(status) => {
+            set_status(config, &mut head, status, log);
+
+            env.push(("BACKUP_OUTCOME".to_string(), "failure".to_string()));
+            env.push(("BACKUP_STATUS".to_string(), status.to_string()));
+
+            if let Some(x) = &config.post_failure {
+                run_hook(config, "post-failure", x, &env, log);
+            }
+
+            control::finished(false, status);
+            let stage = if status == INTERRUPTED {
+                "interrupted"
             } else {
-                walk(
-                    x.path(),
-                    format!("{name}/{}", x.file_name().into_string().unwrap())
-                        .trim_start_matches('/')
-                        .to_string(),
-                    zip,
-                    options,
-                    log,
-                );
+                stage.as_str()
+            };
+            record(config, run, started, Err(stage), log);
+            config.healthchecks.ping(
+                healthcheck::Event::Failure,
+                &format!("{status} (stage: {stage})"),
+                log,
+            );
+            false
+        }
+    }
+}
+
+/// Message ids of a published backup.
+struct Report {
+    /// Chunk messages and SHA-256 hashes of their attachments.
+    chunks: Vec<(NonZeroU64, String)>,
+    /// Download script message and its hash, unsigned backups only.
+    script: Option<(NonZeroU64, String)>,
+    manifest: NonZeroU64,
+    /// Size of the published archive.
+    size: u64,
+}
+
+/// Step of a backup, to tell where one failed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Stage {
+    Setup,
+    PreHook,
+    Script,
+    Archive,
+    Encrypt,
+    Upload,
+    Publish,
+}
+impl Stage {
+    fn as_str(self) -> &'static str {
+        match self {
+            Self::Setup => "setup",
+            Self::PreHook => "pre_hook",
+            Self::Script => "script",
+            Self::Archive => "archive",
+            Self::Encrypt => "encrypt",
+            Self::Upload => "upload",
+            Self::Publish => "publish",
+        }
+    }
+}
+
+/// Post script output next to the head message.
+fn post_output<L: Logger>(webhook: &Webhook, title: &str, output: &ScriptLog, log: &mut L) {
+    let tail = output.tail();
+    // Not worth holding up stopping for
+    let _ = if tail.len() <= 1900 && !tail.contains("```") {
+        webhook.send(
+            |x| x.content(format!("{title}\n```\n{}\n```", tail.trim_end())),
+            log,
+        )
+    } else {
+        webhook.send(
+            |x| {
+                x.content(title)
+                    .file("script.log", tail.clone().into_bytes())
+            },
+            log,
+        )
+    };
+}
+
+/// Write `script` into a temporary file and run it, teeing its output into
+/// `output`.
+///
+/// `interruptible` scripts are asked to stop when we are.
+fn run_script(
+    script: &Script,
+    dir: Option<&Path>,
+    env: &[(String, String)],
+    output: &Arc<Mutex<ScriptLog>>,
+    interruptible: bool,
+) -> std::io::Result<ExitStatus> {
+    let path = Defer::new(temp_path(), |x| {
+        let _ = fs::remove_file(x);
+    });
+    temp::create_file(&path)?.write_all(script.body.as_bytes())?;
+
+    let mut iter = script.shell.iter();
+    let mut command = Command::new(iter.next().unwrap());
+    command
+        .args(iter)
+        .arg(&*path)
+        .envs(env.iter().cloned())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped());
+    if let Some(x) = dir {
+        command.current_dir(x);
+    }
+    let mut proc = command.spawn()?;
+
+    let detached = Arc::new(AtomicBool::new(false));
+    let tees = [
+        tee(
+            proc.stdout.take().unwrap(),
+            std::io::stdout(),
+            output.clone(),
+            detached.clone(),
+        ),
+        tee(
+            proc.stderr.take().unwrap(),
+            std::io::stderr(),
+            output.clone(),
+            detached.clone(),
+        ),
+    ];
+
+    // Poll instead of blocking so the watchdog keeps being fed while the
+    // script runs
+    let mut stopped = false;
+    let status = loop {
+        match proc.try_wait() {
+            Ok(Some(x)) => break Ok(x),
+            Ok(None) => (),
+            Err(why) => break Err(why),
+        }
+        notify::watchdog();
+        if interruptible && signal::stopping() && !stopped {
+            stopped = true;
+            #[cfg(unix)]
+            unsafe {
+                libc::kill(proc.id() as libc::pid_t, libc::SIGTERM);
             }
+            #[cfg(windows)]
+            let _ = proc.kill();
         }
+        std::thread::sleep(Duration::from_millis(100));
+    };
+
+    // Background processes inherit the pipes, waiting for them to close could
+    // take forever
+    let deadline = Instant::now() + OUTPUT_GRACE;
+    while tees.iter().any(|x| !x.is_finished()) && Instant::now() < deadline {
+        std::thread::sleep(Duration::from_millis(10));
     }
-    let password = config.password.as_ref();
-    walk(
-        dir.clone(),
-        String::new(),
-        &mut zip,
-        {
-            let options = FileOptions::default()
-                .compression_level(Some(config.compression_level))
-                .compression_method(zip::CompressionMethod::Deflated);
+    detached.store(true, Ordering::Relaxed);
+    for x in tees.into_iter().filter(|x| x.is_finished()) {
+        let _ = x.join();
+    }
+    status
+}
+
+/// Run a hook script, returns whether it succeeded.
+fn run_hook<L: Logger>(
+    config: &Config,
+    name: &str,
+    script: &Script,
+    env: &[(String, String)],
+    log: &mut L,
+) -> bool {
+    log.info(&format!("Running {name} hook..."));
 
-            if let Some(x) = password {
-                options.with_aes_encryption(zip::AesMode::Aes256, x)
+    let output = Arc::new(Mutex::new(ScriptLog::new(config.script_log_size)));
+    match run_script(script, None, env, &output, name == "pre") {
+        Ok(x) if x.success() => true,
+        Ok(x) => {
+            log.error(&format!("The {name} hook failed: exited with {x}"));
+            post_output(
+                &config.webhook,
+                &format!("`{name}` hook output:"),
+                &output.lock().unwrap(),
+                log,
+            );
+            false
+        }
+        Err(why) => {
+            log.error(&format!("Failed to run the {name} hook: {why}"));
+            false
+        }
+    }
+}
+
+/// Finish setting up the script environment and run the pre hook.
+fn prepare<L: Logger>(
+    config: &Config,
+    env: &mut Vec<(String, String)>,
+    stage: &mut Stage,
+    log: &mut L,
+) -> Result<(), &'static str> {
+    for x in &config.env_files {
+        match read_env_file(x) {
+            Ok(x) => env.extend(x),
+            Err(why) => {
+                log.error(&format!("Failed to read env file: {why}"));
+                return Err("Setup failed");
+            }
+        }
+    }
+    env.extend(config.env.iter().cloned());
+
+    if let Some(x) = &config.pre {
+        *stage = Stage::PreHook;
+        if !run_hook(config, "pre", x, env, log) {
+            if signal::stopping() {
+                return Err(INTERRUPTED);
+            }
+            return Err("Pre-backup hook failed");
+        }
+    }
+
+    Ok(())
+}
+
+/// Encrypt the archive to the configured age recipients into `path`.
+fn seal(config: &Config, mut archive: File, path: &Path) -> std::io::Result<File> {
+    let recipients = config.recipients.iter().map(|x| x as &dyn age::Recipient);
+    let encryptor = age::Encryptor::with_recipients(recipients).map_err(std::io::Error::other)?;
+
+    let file = temp::create_file(path)?;
+    let mut writer = encryptor.wrap_output(std::io::BufWriter::new(file))?;
+    let mut buffer = vec![0u8; 64 * 1024];
+    loop {
+        let len = archive.read(&mut buffer)?;
+        if len == 0 {
+            break;
+        }
+        writer.write_all(&buffer[..len])?;
+        notify::watchdog();
+    }
+    writer.finish()?.flush()?;
+
+    File::open(path)
+}
+
+/// Run the backup script and publish the archive, keeping `stage` up to
+/// date.
+///
+/// On failure returns the text the head message should be set to.
+fn backup<L: Logger>(
+    config: &Config,
+    head: &mut Message,
+    env: &[(String, String)],
+    stage: &mut Stage,
+    log: &mut L,
+) -> Result<Report, &'static str> {
+    *stage = Stage::Setup;
+    let dir = Defer::new(temp_path(), |x| fs::remove_dir_all(x));
+    if let Err(why) = temp::create_dir(&dir) {
+        log.error(&format!("Failed to create dir: {why}"));
+        return Err("Setup failed");
+    }
+
+    let script_log_path = Defer::new(temp_path(), |x| {
+        let _ = fs::remove_file(x);
+    });
+    let script_log = Arc::new(Mutex::new(ScriptLog::new(config.script_log_size)));
+    if config.archive_script_log {
+        match temp::create_file(&script_log_path) {
+            Ok(x) => script_log.lock().unwrap().file = Some(x),
+            Err(why) => {
+                log.error(&format!("Failed to create script log file: {why}"));
+                return Err("Setup failed");
+            }
+        }
+    }
+
+    let mut env = env.to_vec();
+    env.push(("BACKUP_DIR".to_string(), dir.to_string_lossy().into_owned()));
+
+    if let Some(script) = &config.script {
+        *stage = Stage::Script;
+        set_status(config, head, "Backing up data...", log);
+
+        match run_script(script, Some(&dir), &env, &script_log, true) {
+            Ok(_) if signal::stopping() => return Err(INTERRUPTED),
+            Ok(x) => {
+                if !x.success() {
+                    log.error(&format!("Backup process failed: exited with {x}"));
+                    post_output(
+                        &config.webhook,
+                        "Backup script output:",
+                        &script_log.lock().unwrap(),
+                        log,
+                    );
+                    return Err("Backup process failed");
+                }
+            }
+            Err(why) => {
+                log.error(&format!("Failed to start backup process: {why}"));
+                return Err("Failed to start backup process");
+            }
+        }
+    }
+
+    *stage = Stage::Archive;
+    if let Some(available) = temp::available_space() {
+        let mut estimate = Estimate(0);
+        let mut walker = Walker {
+            archive: &mut estimate,
+            max_file_size: config.max_file_size,
+            skipped: 0,
+            log: &mut NullLogger,
+        };
+        // Errors show up again when building the archive
+        if walker.add_all(config, &dir).is_ok() {
+            // Encrypting to recipients keeps a second copy around
+            let needed = if config.recipients.is_empty() {
+                estimate.0
             } else {
-                options
+                estimate.0 * 2
+            };
+            if needed > available {
+                log.error(&format!(
+                    "Not enough free space for the archive: up to {} needed, {} available",
+                    format_size(needed),
+                    format_size(available)
+                ));
+                return Err("Not enough free space for the archive");
             }
-        },
+        }
+    }
+
+    let archive = Defer::new(temp_path(), |x| fs::remove_file(x));
+
+    let file = match temp::create_file(&archive) {
+        Ok(x) => x,
+        Err(why) => {
+            log.error(&format!("Failed to create temporary file: {why}"));
+            return Err("Failed to start backup process");
+        }
+    };
+    let mut writer = match archive::create(config, file) {
+        Ok(x) => x,
+        Err(why) => {
+            log.error(&format!("Failed to create archive: {why}"));
+            return Err("Failed to start backup process");
+        }
+    };
+
+    log.info("Compressing the archive...");
+    set_status(config, head, "Compressing the archive...", log);
+
+    let mut walker = Walker {
+        archive: &mut *writer,
+        max_file_size: config.max_file_size,
+        skipped: 0,
         log,
-    );
+    };
+    walker.add_all(config, &dir)?;
+
+    let skipped = walker.skipped;
+    if skipped != 0 {
+        log.info(&format!("Skipped {skipped} entries"));
+    }
 
-    drop(script);
+    if config.archive_script_log {
+        drop(Arc::into_inner(script_log));
+        if let Err(why) = File::open(&*script_log_path)
+            .and_then(|mut x| writer.add_file("backup-script.log", &x.metadata()?, &mut x))
+        {
+            log.warn(&format!("Failed to add script log to the archive: {why}"));
+        }
+    }
 
-    if let Err(why) = zip.finish() {
-        log.error(&format!("Failed to contruct a zip archive: {why}"));
-        head.edit(&config.webhook, "Failed to finalize a zip archive", log);
-        return;
+    if let Err(why) = writer.finish() {
+        log.error(&format!("Failed to contruct an archive: {why}"));
+        return Err("Failed to finalize an archive");
     }
 
     drop(dir);
@@ -254,16 +859,13 @@ pub fn upload<'a, L: Logger>(config: &'a Config, log: &'a mut L) {
         Ok(x) => x,
         Err(why) => {
             log.error(&format!("Failed to open temporary file: {why}"));
-            head.edit(&config.webhook, "Failed to start backup process", log);
-            return;
+            return Err("Failed to start backup process");
         }
     };
 
-    let volumes = ["B", "KiB", "MiB", "GiB", "TiB"];
-    match file.metadata() {
+    let size = match file.metadata() {
         Ok(x) => {
-            let mut volume = 0;
-            let mut size = {
+            let size = {
                 #[cfg(unix)]
                 {
                     x.size()
@@ -272,165 +874,355 @@ pub fn upload<'a, L: Logger>(config: &'a Config, log: &'a mut L) {
                 {
                     x.file_size()
                 }
-            } as f32;
-            while size >= 1024.0 && volume < volumes.len() - 1 {
-                size /= 1024.0;
-                volume += 1;
-            }
-            log.info(&format!(
-                "Final archive size: {size:0.3}{}",
-                volumes[volume]
-            ));
+            };
+            log.info(&format!("Final archive size: {}", format_size(size)));
+            size
         }
         Err(why) => {
             log.error(&format!("Failed to fetch file metadata: {why}"));
-            head.edit(&config.webhook, "Failed to fetch file metadata", log);
-            return;
+            return Err("Failed to fetch file metadata");
         }
-    }
+    };
+
+    // Encrypted archives can't be unpacked directly, restore decrypts them
+    let encrypted = config.password.is_some() && config.encryption == Encryption::Archive;
+    let ext = if !config.recipients.is_empty() {
+        format!("{}.age", config.format.extension())
+    } else if encrypted {
+        format!("{}.enc", config.format.extension())
+    } else {
+        config.format.extension().to_string()
+    };
+
+    let sealed = Defer::new(temp_path(), |x| {
+        let _ = fs::remove_file(x);
+    });
+    let (file, size): (Box<dyn Read>, u64) = if !config.recipients.is_empty() {
+        *stage = Stage::Encrypt;
+        set_status(config, head, "Encrypting the archive...", log);
+        match seal(config, file, &sealed) {
+            Ok(x) => {
+                let size = x.metadata().map_or(size, |x| x.len());
+                (Box::new(x), size)
+            }
+            Err(why) => {
+                log.error(&format!("Failed to encrypt archive: {why}"));
+                return Err("Failed to encrypt archive");
+            }
+        }
+    } else if let (Some(password), true) = (&config.password, encrypted) {
+        *stage = Stage::Encrypt;
+        match Encryptor::new(file, password) {
+            Ok(x) => (Box::new(x), crypt::encrypted_len(size)),
+            Err(why) => {
+                log.error(&format!("Failed to encrypt archive: {why}"));
+                return Err("Failed to encrypt archive");
+            }
+        }
+    } else {
+        (Box::new(file), size)
+    };
 
-    head.edit(&config.webhook, "Publishing artifact...", log);
+    if signal::stopping() {
+        return Err(INTERRUPTED);
+    }
+    *stage = Stage::Upload;
+    set_status(config, head, "Publishing artifact...", log);
 
     let delete_file = |x: &mut PathBuf| {
         let _ = fs::remove_file(x).ok();
     };
 
     let mut script_path = Defer::new(temp_path(), delete_file);
-    let mut script_file = Rc::new(Mutex::new(
-        match File::options()
-            .write(true)
-            .truncate(true)
-            .create_new(true)
-            .open(&*script_path)
-        {
-            Ok(x) => x,
-            Err(why) => {
-                log.error(&format!("Failed to create download script: {why}"));
-                head.edit(&config.webhook, "Failed to create download script", log);
-                return;
-            }
-        },
-    ));
+    let mut script_file = Rc::new(Mutex::new(match temp::create_file(&script_path) {
+        Ok(x) => x,
+        Err(why) => {
+            log.error(&format!("Failed to create download script: {why}"));
+            return Err("Failed to create download script");
+        }
+    }));
 
     if let Err(why) = script_file.lock().unwrap()
-        .write_all(format!(r#"dl(){{ curl -f -L "$(curl -f -L "{}/messages/$1"|grep -Eo '"url":"[^"]+"'|grep -Eo 'https[^"]+')">>dl_backup.zip;if [ ! $? -eq 0 ];then sleep 5;dl "$1";fi }};printf "">dl_backup.zip"#, config.webhook.url()).as_bytes())
+        .write_all(format!(r#"[ -n "$DBU_WEBHOOK" ]||{{ echo "Set DBU_WEBHOOK to the webhook URL">&2;exit 1; }};dl(){{ curl -f -L "$(curl -f -L "$DBU_WEBHOOK/messages/$1"|grep -Eo '"url":"[^"]+"'|grep -Eo 'https[^"]+')">>dl_backup.{ext};if [ ! $? -eq 0 ];then sleep 5;dl "$1";fi }};printf "">dl_backup.{ext}"#).as_bytes())
     {
         log.error(&format!("Failed to create download script: {why}"));
-        head.edit(&config.webhook, "Failed to create download script", log);
-        return;
+        return Err("Failed to create download script");
     }
 
+    // A chunk is sent for the end even if it's empty
+    let total = size / (1000 * 1000 * config.block_size as u64) + 1;
+    chunk_progress(0, total);
+
+    let chunk_ids = Mutex::new(vec![]);
     let chunks = match upload_chunked(
         config.block_size,
         &config.webhook,
         file,
-        |i| format!("chunk_{i}.zip"),
-        |msg, _| {
+        |i| format!("chunk_{i}.{ext}"),
+        |msg, data| {
+            let done = {
+                let mut ids = chunk_ids.lock().unwrap();
+                ids.push((msg.id.unwrap(), manifest::sha256(data)));
+                ids.len() as u64
+            };
+            chunk_progress(done, total);
             script_file
                 .lock()
                 .unwrap()
-                .write_all(format!(";dl {}", msg.id.unwrap()).as_bytes())
+                .write_all(format!(";dl {}", msg.id.unwrap()).as_bytes())?;
+            // Stop once the chunk in flight is done
+            if signal::stopping() {
+                return Err(std::io::Error::new(
+                    ErrorKind::Interrupted,
+                    "backup interrupted",
+                ));
+            }
+            Ok(())
         },
         log,
     ) {
         Ok(x) => x + 1,
+        Err(_) if signal::stopping() => return Err(INTERRUPTED),
         Err(why) => {
             log.error(&format!("Failed to upload artifact: {why}"));
-            head.edit(&config.webhook, "Failed to upload artifact", log);
-            return;
+            return Err("Failed to upload artifact");
         }
     };
 
-    head.edit(&config.webhook, "Uploading download script...", log);
-    config.webhook.send(|x| x.content(":warning: Do not manually download files below! :warning:\n\nThose are for the download script."), log);
-
-    let mut lol = 0usize;
+    let hint = if !config.recipients.is_empty() {
+        format!(
+            r#";echo "Saved dl_backup.{ext}, decrypt it with 'age -d -i <identity> -o dl_backup.{} dl_backup.{ext}' or restore it with 'discord-backup-util --restore dl_backup.{ext} <directory> --identity <identity> <config>'">&2"#,
+            config.format.extension(),
+        )
+    } else if encrypted {
+        format!(
+            r#";echo "Saved dl_backup.{ext}, decrypt and unpack it with 'discord-backup-util --restore dl_backup.{ext} <directory> <config>'">&2"#
+        )
+    } else {
+        format!(
+            r#";echo "Saved dl_backup.{ext}, unpack it with '{} dl_backup.{ext}'">&2"#,
+            config.format.unpack_command(),
+        )
+    };
+    if let Err(why) = script_file.lock().unwrap().write_all(hint.as_bytes()) {
+        log.error(&format!("Failed to create download script: {why}"));
+        return Err("Failed to create download script");
+    }
 
-    loop {
-        if let Err(why) = script_file.lock().unwrap().flush() {
-            log.error(&format!("Failed to upload download script: {why}"));
-            head.edit(&config.webhook, "Failed to upload download script", log);
-            return;
+    *stage = Stage::Publish;
+    // Anyone with the webhook can replace the script, signed backups are
+    // only offered through the manifest so the signature is checked
+    let script = if config.signing_key.is_some() {
+        None
+    } else {
+        set_status(config, head, "Uploading download script...", log);
+        if config.webhook.send(|x| x.content(":warning: Do not manually download files below! :warning:\n\nThose are for the download script."), log).is_err() {
+            return Err(INTERRUPTED);
         }
 
-        script_file = Rc::new(Mutex::new(match File::open(&*script_path) {
-            Ok(x) => x,
-            Err(why) => {
+        let mut lol = 0usize;
+
+        Some(loop {
+            if let Err(why) = script_file.lock().unwrap().flush() {
                 log.error(&format!("Failed to upload download script: {why}"));
-                head.edit(&config.webhook, "Failed to upload download script", log);
-                return;
+                return Err("Failed to upload download script");
             }
-        }));
-
-        let overflow_path = Defer::new(temp_path(), delete_file);
-        let overflow_file = Rc::new(Mutex::new(
-            match File::options()
-                .write(true)
-                .truncate(true)
-                .create_new(true)
-                .open(&*overflow_path)
-            {
+
+            script_file = Rc::new(Mutex::new(match File::open(&*script_path) {
                 Ok(x) => x,
                 Err(why) => {
                     log.error(&format!("Failed to upload download script: {why}"));
-                    head.edit(&config.webhook, "Failed to upload download script", log);
-                    return;
+                    return Err("Failed to upload download script");
                 }
-            },
-        ));
+            }));
 
-        if let Err(why) = overflow_file.lock().unwrap()
-            .write_all(format!(r#"TFILE=mktemp;dl(){{ curl -f -L "$(curl -f -L "{}/messages/$1"|grep -Eo '"url":"[^"]+"'|grep -Eo 'https[^"]+')">>$TFILE;if [ ! $? -eq 0 ];then sleep 5;dl "$1";fi }};printf "">$TFILE"#, config.webhook.url()).as_bytes())
-        {
-            log.error(&format!("Failed to upload download script: {why}"));
-            head.edit(&config.webhook, "Failed to upload download script", log);
-            return;
-        }
+            let overflow_path = Defer::new(temp_path(), delete_file);
+            let overflow_file = Rc::new(Mutex::new(match temp::create_file(&overflow_path) {
+                Ok(x) => x,
+                Err(why) => {
+                    log.error(&format!("Failed to upload download script: {why}"));
+                    return Err("Failed to upload download script");
+                }
+            }));
+
+            if let Err(why) = overflow_file
+                .lock()
+                .unwrap()
+                .write_all(OVERFLOW_HEAD.as_bytes())
+            {
+                log.error(&format!("Failed to upload download script: {why}"));
+                return Err("Failed to upload download script");
+            }
 
-        let message_id = Rc::new(AtomicU64::default());
+            let message_id = Rc::new(AtomicU64::default());
+            let script_hash = Rc::new(Mutex::new(String::new()));
 
-        match upload_chunked(
-            config.block_size,
-            &config.webhook,
-            &mut *script_file.lock().unwrap(),
-            |i| format!("script_{lol}_{i}.zip"),
-            |msg, _| {
-                message_id.store(msg.id.unwrap().get(), std::sync::atomic::Ordering::SeqCst);
-                overflow_file
-                    .lock()
-                    .unwrap()
-                    .write_all(format!(";dl {}", msg.id.unwrap()).as_bytes())
-            },
-            log,
-        ) {
-            Ok(0) => {
-                config.webhook.send(|x| x.content(format!("Upload complete!\n\nTo automatically download the backup archive, use the following script:```sh\ncurl -f -L \"$(curl -f -L \"{}/messages/{}\" | grep -Eo '\"url\":\"[^\"]+\"' | grep -Eo 'https[^\"]+')\" | sh -\n```\n\nMake sure `curl` and `grep` are installed.", config.webhook.url(), message_id.load(std::sync::atomic::Ordering::SeqCst))), log);
-                break;
+            match upload_chunked(
+                config.block_size,
+                &config.webhook,
+                &mut *script_file.lock().unwrap(),
+                |i| format!("script_{lol}_{i}.zip"),
+                |msg, data| {
+                    message_id.store(msg.id.unwrap().get(), std::sync::atomic::Ordering::SeqCst);
+                    *script_hash.lock().unwrap() = manifest::sha256(data);
+                    overflow_file
+                        .lock()
+                        .unwrap()
+                        .write_all(format!(";dl {}", msg.id.unwrap()).as_bytes())
+                },
+                log,
+            ) {
+                Ok(0) => {
+                    let text = format!("Upload complete!\n\nTo automatically download the backup archive, use the following script:```sh\nexport DBU_WEBHOOK='<webhook url>'\ncurl -f -L \"$(curl -f -L \"$DBU_WEBHOOK/messages/{}\" | grep -Eo '\"url\":\"[^\"]+\"' | grep -Eo 'https[^\"]+')\" | sh -\n```\n\nMake sure `curl` and `grep` are installed.", message_id.load(std::sync::atomic::Ordering::SeqCst));
+                    if config
+                        .webhook
+                        .send(|x| x.content(text.clone()), log)
+                        .is_err()
+                    {
+                        return Err(INTERRUPTED);
+                    }
+                    break (
+                        NonZeroU64::new(message_id.load(std::sync::atomic::Ordering::SeqCst))
+                            .unwrap(),
+                        script_hash.lock().unwrap().clone(),
+                    );
+                }
+                Err(why) => {
+                    log.error(&format!("Failed to upload download script: {why}"));
+                    return Err("Failed to upload download script");
+                }
+                _ => (),
             }
-            Err(why) => {
+
+            if let Err(why) = overflow_file
+                .lock()
+                .unwrap()
+                .write_all(OVERFLOW_TAIL.as_bytes())
+            {
                 log.error(&format!("Failed to upload download script: {why}"));
-                head.edit(&config.webhook, "Failed to upload download script", log);
-                return;
+                return Err("Failed to upload download script");
             }
-            _ => (),
-        }
 
-        if let Err(why) = overflow_file
-            .lock()
-            .unwrap()
-            .write_all(r#";sh $TFILE;rm $TFILE"#.as_bytes())
-        {
-            log.error(&format!("Failed to upload download script: {why}"));
-            head.edit(&config.webhook, "Failed to upload download script", log);
-            return;
-        }
+            script_path = overflow_path;
 
-        script_path = overflow_path;
+            lol += 1;
+        })
+    };
 
-        lol += 1;
+    let chunk_ids = chunk_ids.into_inner().unwrap();
+    let manifest = Manifest {
+        job: config.name.clone(),
+        created: SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|x| x.as_secs())
+            .unwrap_or_default(),
+        extension: ext,
+        chunks: chunk_ids.clone(),
+        script: script.clone(),
     }
+    .to_json();
+    let signature = config.signing_key.as_ref().map(|x| {
+        log.info(&format!(
+            "Signing manifest with key {}",
+            manifest::public_key(x)
+        ));
+        manifest::sign(x, manifest.as_bytes())
+    });
 
-    head.edit(&config.webhook, format!("Backup completed successfully.\n\nTo assemble the original archive, download all {chunks} chunks and concatenate them into a single file"), log);
+    let Ok(manifest_message) = config.webhook.send(
+        |x| {
+            let x = x
+                .content("Backup manifest")
+                .file("manifest.json", manifest.clone().into_bytes());
+            match &signature {
+                Some(signature) => x.file("manifest.sig", signature.clone().into_bytes()),
+                None => x,
+            }
+        },
+        log,
+    ) else {
+        return Err(INTERRUPTED);
+    };
+    let manifest_id = manifest_message.id.unwrap();
+
+    let restore = if signature.is_some() {
+        format!("The manifest is signed, run `discord-backup-util --restore {manifest_id} <directory> <config>` with verify-key set to download the archive and check it against the signature.")
+    } else {
+        format!("To assemble the original archive, download all {chunks} chunks and concatenate them into a single file, or run `discord-backup-util --restore {manifest_id} <directory> <config>`")
+    };
+    set_status(
+        config,
+        head,
+        format!(
+            "Backup completed successfully.\n\n{restore}{}",
+            if skipped == 0 {
+                String::new()
+            } else {
+                format!("\n\n{skipped} entries were skipped")
+            }
+        ),
+        log,
+    );
+
+    log.info("Backup completed successfully");
 
-    println!("Backup completed successfully");
+    Ok(Report {
+        chunks: chunk_ids,
+        script,
+        manifest: manifest_id,
+        size,
+    })
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use std::{fs, os::unix::fs::PermissionsExt, process::Command};
+
+    use super::{OVERFLOW_HEAD, OVERFLOW_TAIL};
+
+    #[test]
+    fn overflow_script_runs_its_parts() {
+        let dir = std::env::temp_dir().join(format!("dbu-overflow-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let (bin, cwd, tmp) = (dir.join("bin"), dir.join("cwd"), dir.join("tmp"));
+        for x in [&bin, &cwd, &tmp] {
+            fs::create_dir_all(x).unwrap();
+        }
+
+        // Messages point to an attachment echoing their id, and the parts
+        // must not land in the working directory
+        fs::write(
+            bin.join("curl"),
+            r#"#!/bin/sh
+case "$3" in
+    */messages/*) printf '{"url":"https://cdn/%s"}' "${3##*/}";;
+    https://cdn/*) ls -A >&2; printf 'echo part %s;' "${3##*/}";;
+    *) exit 1;;
+esac
+"#,
+        )
+        .unwrap();
+        fs::set_permissions(bin.join("curl"), fs::Permissions::from_mode(0o755)).unwrap();
+
+        let output = Command::new("sh")
+            .arg("-c")
+            .arg(format!("{OVERFLOW_HEAD};dl 1;dl 2{OVERFLOW_TAIL}"))
+            .current_dir(&cwd)
+            .env(
+                "PATH",
+                format!("{}:{}", bin.display(), std::env::var("PATH").unwrap()),
+            )
+            .env("DBU_WEBHOOK", "http://hook")
+            .env("TMPDIR", &tmp)
+            .output()
+            .unwrap();
+
+        assert!(output.status.success());
+        assert_eq!(String::from_utf8_lossy(&output.stdout), "part 1\npart 2\n");
+        assert_eq!(String::from_utf8_lossy(&output.stderr), "");
+        assert_eq!(fs::read_dir(&cwd).unwrap().count(), 0);
+        assert_eq!(fs::read_dir(&tmp).unwrap().count(), 0);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
 }
@@ -0,0 +1,578 @@
+mod common;
+
+use std::{
+    fs,
+    time::{Duration, Instant},
+};
+
+use common::{assert_same, config, run, sample_data, workdir, Server, BACKENDS};
+
+/// Back up, then restore from the manifest and compare with the original.
+fn round_trip(name: &str, backend: &str, extra: &str) -> Server {
+    let dir = workdir(name);
+    sample_data(&dir.join("data"), 2_100_000);
+    let server = Server::start();
+    let config = config(&server, &dir, &format!("http-backend {backend}\n{extra}"));
+
+    let output = run(&dir, &["--once", &config.to_string_lossy()]);
+    assert!(output.status.success(), "{}", output.stdout);
+    assert!(output.stdout.contains("Backup completed successfully"));
+
+    let (_, head) = server.messages().into_iter().next().unwrap();
+    assert!(head
+        .content
+        .unwrap()
+        .starts_with("Backup completed successfully."));
+    let chunks = server
+        .messages()
+        .iter()
+        .filter(|(_, x)| x.files.iter().any(|(x, _)| x.starts_with("chunk_")))
+        .count();
+    assert!(chunks >= 3, "expected several chunks, got {chunks}");
+
+    let manifest = server.manifest().unwrap().to_string();
+    let destination = dir.join("restored");
+    let destination_arg = destination.to_string_lossy();
+    let config_arg = config.to_string_lossy();
+    let mut args = vec!["--restore", &manifest, &destination_arg, &config_arg];
+    if !extra.contains("verify-key") {
+        let output = run(&dir, &args);
+        assert!(!output.status.success(), "{}", output.stdout);
+        assert!(
+            output.stderr.contains("No verify-key is set"),
+            "{}",
+            output.stderr
+        );
+        args.insert(0, "--no-verify");
+    }
+    let output = run(&dir, &args);
+    assert!(output.status.success(), "{}", output.stdout);
+    assert_same(&dir.join("data"), &destination.join("data"));
+
+    fs::remove_dir_all(dir).unwrap();
+    server
+}
+
+#[test]
+fn dot_dirs_keep_their_name() {
+    let server = Server::start();
+    let dir = workdir("dot-dirs");
+    sample_data(&dir.join("data"), 1000);
+    sample_data(&dir.join(".ssh"), 1000);
+    let config = config(&server, &dir, "include ./.ssh\n");
+
+    let output = run(&dir, &["--once", &config.to_string_lossy()]);
+    assert!(output.status.success(), "{}", output.stderr);
+
+    let manifest = server.manifest().unwrap().to_string();
+    let destination = dir.join("restored");
+    let output = run(
+        &dir,
+        &[
+            "--restore",
+            &manifest,
+            &destination.to_string_lossy(),
+            "--no-verify",
+            &config.to_string_lossy(),
+        ],
+    );
+    assert!(output.status.success(), "{}", output.stderr);
+    assert_same(&dir.join(".ssh"), &destination.join(".ssh"));
+
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn zip_round_trip() {
+    for backend in BACKENDS {
+        let server = round_trip(&format!("zip-{backend}"), backend, "");
+        assert!(server.requests().iter().all(|x| x
+            .header("user-agent")
+            .is_some_and(|x| x.starts_with("discord-backup-util/"))));
+    }
+}
+
+#[test]
+fn tar_round_trip() {
+    for backend in BACKENDS {
+        round_trip(&format!("tar-{backend}"), backend, "format tar.zst\n");
+    }
+}
+
+#[test]
+fn encrypted_signed_round_trip() {
+    let dir = workdir("keygen");
+    let keys = run(&dir, &["--keygen"]).stdout;
+    fs::remove_dir_all(dir).unwrap();
+
+    // Encryption doesn't depend on the HTTP backend
+    let server = round_trip(
+        "encrypted",
+        BACKENDS[0],
+        &format!("format tar.gz\npassword hunter2\n{keys}"),
+    );
+    // The unsigned download script isn't uploaded for signed backups
+    assert!(server.messages().iter().all(|(_, x)| {
+        !x.content
+            .as_ref()
+            .is_some_and(|x| x.contains("download script"))
+            && x.files.iter().all(|(name, _)| !name.starts_with("script_"))
+    }));
+    round_trip(
+        "encrypted-zip",
+        BACKENDS[0],
+        "password hunter2\nencryption archive\n",
+    );
+}
+
+#[cfg(unix)]
+#[test]
+fn download_script_fetches_the_archive() {
+    use std::{os::unix::fs::PermissionsExt, process::Command};
+
+    let server = Server::start();
+    let dir = workdir("download-script");
+    sample_data(&dir.join("data"), 2_100_000);
+    let config = config(&server, &dir, "");
+
+    let output = run(&dir, &["--once", &config.to_string_lossy()]);
+    assert!(output.status.success(), "{}", output.stdout);
+
+    let snippet = server
+        .messages()
+        .into_iter()
+        .find_map(|(_, x)| {
+            let content = x.content?;
+            let (_, rest) = content.split_once("```sh\n")?;
+            Some(rest.split_once("```")?.0.to_string())
+        })
+        .expect("no download script was posted");
+    let snippet = snippet.replace("<webhook url>", &server.webhook());
+
+    // Attachment URLs need to look like Discord's https ones to the script,
+    // but the mock server only speaks http
+    let curl = std::env::split_paths(&std::env::var_os("PATH").unwrap())
+        .map(|x| x.join("curl"))
+        .find(|x| x.is_file())
+        .expect("curl is needed to run the download script");
+    let bin = dir.join("bin");
+    fs::create_dir(&bin).unwrap();
+    fs::write(
+        bin.join("curl"),
+        format!(
+            r#"#!/bin/sh
+url=$(printf %s "$3" | sed 's,^https://,http://,')
+case "$url" in
+    */messages/*) {curl} "$1" "$2" "$url" | sed 's,"http://,"https://,g';;
+    *) exec {curl} "$1" "$2" "$url";;
+esac
+"#,
+            curl = curl.display()
+        ),
+    )
+    .unwrap();
+    fs::set_permissions(bin.join("curl"), fs::Permissions::from_mode(0o755)).unwrap();
+
+    let download = dir.join("download");
+    fs::create_dir(&download).unwrap();
+    let output = Command::new("sh")
+        .arg("-c")
+        .arg(&snippet)
+        .current_dir(&download)
+        .env(
+            "PATH",
+            format!("{}:{}", bin.display(), std::env::var("PATH").unwrap()),
+        )
+        .output()
+        .unwrap();
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+
+    let destination = dir.join("restored");
+    let output = run(
+        &dir,
+        &[
+            "--restore",
+            &download.join("dl_backup.zip").to_string_lossy(),
+            &destination.to_string_lossy(),
+            &config.to_string_lossy(),
+        ],
+    );
+    assert!(output.status.success(), "{}", output.stderr);
+    assert_same(&dir.join("data"), &destination.join("data"));
+
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn rate_limits_are_retried() {
+    for backend in BACKENDS {
+        let server = Server::start();
+        server.queue(
+            429,
+            r#"{"message": "You are being rate limited.", "retry_after": 0.1, "global": false}"#,
+        );
+
+        let dir = workdir(&format!("ratelimit-{backend}"));
+        sample_data(&dir.join("data"), 1000);
+        let config = config(&server, &dir, &format!("http-backend {backend}\n"));
+
+        let output = run(&dir, &["--once", &config.to_string_lossy()]);
+        assert!(output.status.success(), "{}", output.stdout);
+        assert!(output.stderr.contains("Rate limited"));
+
+        let requests = server.requests();
+        assert_eq!(requests[0].status, 429);
+        assert_eq!(requests[1].method, "POST");
+        assert_eq!(requests[1].status, 200);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
+
+#[test]
+fn stalled_requests_time_out() {
+    for backend in BACKENDS {
+        let server = Server::start();
+        server.stall(Duration::from_secs(5));
+
+        let dir = workdir(&format!("stall-{backend}"));
+        sample_data(&dir.join("data"), 1000);
+        let config = config(
+            &server,
+            &dir,
+            &format!("http-backend {backend}\nhttp-timeout 1s\n"),
+        );
+
+        let output = run(&dir, &["--once", &config.to_string_lossy()]);
+        assert!(output.status.success(), "{}", output.stdout);
+
+        let requests = server.requests();
+        assert_eq!(requests[0].status, 0);
+        assert_eq!(requests[1].method, "POST");
+        assert_eq!(requests[1].status, 200);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
+
+#[test]
+fn bandwidth_limit_slows_uploads() {
+    for backend in BACKENDS {
+        let server = Server::start();
+        let dir = workdir(&format!("bandwidth-{backend}"));
+        sample_data(&dir.join("data"), 600_000);
+        let config = config(
+            &server,
+            &dir,
+            &format!("http-backend {backend}\nbandwidth-limit 200kB/s\n"),
+        );
+
+        let started = Instant::now();
+        let output = run(&dir, &["--once", &config.to_string_lossy()]);
+        assert!(output.status.success(), "{}", output.stdout);
+        let elapsed = started.elapsed();
+        assert!(elapsed > Duration::from_millis(2500), "{elapsed:?}");
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
+
+#[test]
+fn oversized_chunks_fail_the_backup() {
+    for backend in BACKENDS {
+        let server = Server::start();
+        server.set_max_upload(200_000);
+
+        let dir = workdir(&format!("oversized-{backend}"));
+        sample_data(&dir.join("data"), 1_500_000);
+        let config = config(&server, &dir, &format!("http-backend {backend}\n"));
+
+        let output = run(&dir, &["--once", &config.to_string_lossy()]);
+        assert!(!output.status.success(), "{}", output.stdout);
+        assert!(output.stderr.contains("413"), "{}", output.stderr);
+
+        let (_, head) = server.messages().into_iter().next().unwrap();
+        assert_eq!(head.content.as_deref(), Some("Failed to upload artifact"));
+        assert!(server.manifest().is_none());
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
+
+#[test]
+fn failure_report_keeps_script_output() {
+    let server = Server::start();
+    let dir = workdir("failure");
+    sample_data(&dir.join("data"), 1000);
+    let config = config(
+        &server,
+        &dir,
+        "#!/bin/sh\nprintf '\\033[31mfailed to dump \"db\"\\033[0m\\n'\nexit 3\n",
+    );
+
+    let output = run(&dir, &["--once", &config.to_string_lossy()]);
+    assert!(!output.status.success());
+
+    let messages = server.messages();
+    assert_eq!(
+        messages[0].1.content.as_deref(),
+        Some("Backup process failed")
+    );
+    assert!(
+        messages.iter().any(|(_, x)| x
+            .content
+            .as_ref()
+            .is_some_and(|x| x.contains("\u{1b}[31mfailed to dump \"db\"\u{1b}[0m"))),
+        "{messages:?}"
+    );
+
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn background_processes_do_not_hold_up_the_backup() {
+    let server = Server::start();
+    let dir = workdir("background");
+    sample_data(&dir.join("data"), 1000);
+    // The sleep keeps the script's stdout and stderr open
+    let config = config(&server, &dir, "#!/bin/sh\nsleep 30 &\necho started\n");
+
+    let started = Instant::now();
+    let output = run(&dir, &["--once", &config.to_string_lossy()]);
+    assert!(output.status.success(), "{}", output.stderr);
+    assert!(output.stdout.contains("started"), "{}", output.stdout);
+    let elapsed = started.elapsed();
+    assert!(elapsed < Duration::from_secs(20), "{elapsed:?}");
+
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn run_state_carries_over() {
+    let server = Server::start();
+    let dir = workdir("run-state");
+    sample_data(&dir.join("data"), 1000);
+    let runs = dir.join("runs");
+    let config = config(
+        &server,
+        &dir,
+        &format!(
+            "#!/bin/sh\necho \"$BACKUP_RUN_ID $BACKUP_LAST_SUCCESS\" >> {}\n",
+            runs.display()
+        ),
+    );
+
+    for _ in 0..2 {
+        let output = run(&dir, &["--once", &config.to_string_lossy()]);
+        assert!(output.status.success(), "{}", output.stderr);
+    }
+    // Kept in the user's state dir, not the temp dir cleaners empty
+    let kept: Vec<_> = fs::read_dir(dir.join("state/discord-backup-util"))
+        .unwrap()
+        .map(|x| x.unwrap().file_name().into_string().unwrap())
+        .collect();
+    assert!(kept.len() == 1 && kept[0].ends_with(".state"), "{kept:?}");
+
+    let runs = fs::read_to_string(runs).unwrap();
+    let runs: Vec<_> = runs.lines().collect();
+    assert_eq!(runs.len(), 2, "{runs:?}");
+    assert_eq!(runs[0], "1 ");
+    let (id, last_success) = runs[1].split_once(' ').unwrap();
+    assert_eq!(id, "2");
+    assert!(last_success.parse::<u64>().is_ok(), "{runs:?}");
+
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn excessive_argon2_costs_are_refused() {
+    let dir = workdir("argon2-costs");
+    let config = dir.join("backup_config");
+    fs::write(
+        &config,
+        format!(
+            "webhook http://127.0.0.1:1/\nevery 1 day\npassword hunter2\ninclude {}\n",
+            dir.display()
+        ),
+    )
+    .unwrap();
+
+    // 4TiB of memory
+    let mut archive = b"DBUCRYPT".to_vec();
+    archive.extend(u32::MAX.to_le_bytes());
+    archive.extend(2u32.to_le_bytes());
+    archive.extend(1u32.to_le_bytes());
+    archive.extend([0; 16 + 19 + 64]);
+    fs::write(dir.join("crafted.enc"), archive).unwrap();
+
+    let output = run(
+        &dir,
+        &[
+            "--restore",
+            "crafted.enc",
+            "restored",
+            &config.to_string_lossy(),
+        ],
+    );
+    assert!(!output.status.success());
+    assert!(
+        output.stderr.contains("exceed the limits"),
+        "{}",
+        output.stderr
+    );
+
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn healthchecks_are_pinged() {
+    let server = Server::start();
+    let dir = workdir("healthcheck");
+    sample_data(&dir.join("data"), 1000);
+    let healthcheck = server.healthcheck();
+    let checks = format!(
+        "healthcheck-url {healthcheck}\nhealthcheck-url failure {healthcheck}/explicit\nhealthcheck-url start http://127.0.0.1:1/ping/dead\n"
+    );
+
+    let output = run(
+        &dir,
+        &["--once", &config(&server, &dir, &checks).to_string_lossy()],
+    );
+    assert!(output.status.success(), "{}", output.stdout);
+    // A dead monitor doesn't fail the backup, and its URL stays secret
+    assert!(
+        output
+            .stderr
+            .contains("Failed to send the start ping to http://127.0.0.1:1:"),
+        "{}",
+        output.stderr
+    );
+    assert!(!output.stdout.contains("/ping/dead"), "{}", output.stdout);
+    assert!(!output.stderr.contains("/ping/dead"), "{}", output.stderr);
+    assert_eq!(
+        server.pings(),
+        [
+            ("/ping/0f4c3b2a/start".into(), "".into()),
+            (
+                "/ping/0f4c3b2a".into(),
+                "Backup completed successfully".into()
+            ),
+        ]
+    );
+
+    let failing = format!("{checks}#!/bin/sh\nexit 1\n");
+    let output = run(
+        &dir,
+        &["--once", &config(&server, &dir, &failing).to_string_lossy()],
+    );
+    assert!(!output.status.success());
+    let pings = server.pings();
+    assert_eq!(
+        pings[3..],
+        [
+            (
+                "/ping/0f4c3b2a/fail".into(),
+                "Backup process failed (stage: script)".into()
+            ),
+            (
+                "/ping/0f4c3b2a/explicit".into(),
+                "Backup process failed (stage: script)".into()
+            ),
+        ]
+    );
+
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn invalid_configs_are_rejected() {
+    let dir = workdir("invalid");
+    fs::write(dir.join("ca.pem"), "not a certificate\n").unwrap();
+    let cases = [
+        ("every 1 day\n", "missing webhook directive"),
+        ("webhook http://127.0.0.1:1/\n", "missing every directive"),
+        (
+            "webhook http://127.0.0.1:1/\nevery 1 day\nformat tar.gz\ncompress * store\n",
+            "only supported for the zip format",
+        ),
+        (
+            "webhook http://127.0.0.1:1/\nevery 1 day\nhttp-backend curl\n",
+            "unknown or disabled http backend",
+        ),
+        #[cfg(not(windows))]
+        (
+            "webhook http://127.0.0.1:1/\nevery 1 day\nbandwidth-limit 2MiB/s 8-18\n",
+            "expected a time range",
+        ),
+        #[cfg(windows)]
+        (
+            "webhook http://127.0.0.1:1/\nevery 1 day\nbandwidth-limit 2MiB/s 08:00-18:00\n",
+            "not supported on Windows",
+        ),
+        (
+            "webhook http://127.0.0.1:1/\nevery 1 day\nhealthcheck-url finish https://hc-ping.com/x\n",
+            "unknown healthcheck event",
+        ),
+        (
+            "webhook http://127.0.0.1:1/\nevery 1 day\nlock maybe\n",
+            "unknown lock mode",
+        ),
+        (
+            "webhook http://127.0.0.1:1/\nevery 1 day\ninclude ..\n",
+            "invalid archive path",
+        ),
+        (
+            "webhook http://127.0.0.1:1/\nevery 1 day\ninclude /tmp as a/../b\n",
+            "invalid archive path",
+        ),
+        (
+            "webhook http://127.0.0.1:1/\nevery 1 day\nlog-level loud\n",
+            "unknown log level",
+        ),
+        (
+            "webhook http://127.0.0.1:1/\nevery 1 day\nlog-file /nonexistent/backup.log\n",
+            "is not in a directory",
+        ),
+        (
+            "webhook http://127.0.0.1:1/\nevery 0 days\n",
+            "failed to parse every",
+        ),
+        (
+            "webhook http://127.0.0.1:1/\nevery 1 day\nhttp-timeout soon\n",
+            "failed to parse http timeout",
+        ),
+        (
+            "webhook http://127.0.0.1:1/\nevery 1 day\nca-file /nonexistent/ca.pem\n",
+            "does not exist",
+        ),
+        #[cfg(feature = "ureq")]
+        (
+            "webhook http://127.0.0.1:1/\nevery 1 day\nhttp-backend ureq\nca-file ca.pem\n",
+            "no PEM certificates found",
+        ),
+        #[cfg(feature = "minreq")]
+        (
+            "webhook http://127.0.0.1:1/\nevery 1 day\nhttp-backend minreq\nca-file ca.pem\n",
+            "ca-file is not supported by minreq",
+        ),
+        #[cfg(feature = "minreq")]
+        (
+            "webhook http://127.0.0.1:1/\nevery 1 day\nhttp-backend minreq\nproxy socks5://127.0.0.1:1080\n",
+            "minreq only supports http proxies",
+        ),
+    ];
+
+    for (config, error) in cases {
+        let path = dir.join("backup_config");
+        fs::write(&path, format!("include {}\n{config}", dir.display())).unwrap();
+        let output = run(&dir, &["--once", &path.to_string_lossy()]);
+        assert!(!output.status.success(), "{config}");
+        assert!(output.stderr.contains(error), "{config}: {}", output.stderr);
+    }
+
+    fs::remove_dir_all(dir).unwrap();
+}
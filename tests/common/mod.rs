@@ -0,0 +1,605 @@
+//! A local HTTP server that behaves like a Discord webhook, covering what
+//! backups and restores use: posting messages with `?wait=true`, reading,
+//! editing and deleting them, attachment downloads, rate limits and size
+//! limits.
+
+#![allow(dead_code)]
+
+use std::{
+    collections::{BTreeMap, HashMap, VecDeque},
+    fs,
+    io::{BufRead, BufReader, Read, Write},
+    net::{SocketAddr, TcpListener, TcpStream},
+    path::{Path, PathBuf},
+    process::{Child, Command, ExitStatus, Stdio},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use tinyjson::JsonValue;
+
+const PREFIX: &str = "/api/webhooks/1/token";
+
+#[derive(Debug, Clone, Default)]
+pub struct Message {
+    pub content: Option<String>,
+    pub files: Vec<(String, Vec<u8>)>,
+}
+
+/// A request as seen by the server, along with the status it got.
+#[derive(Debug, Clone)]
+pub struct Logged {
+    pub method: String,
+    pub path: String,
+    pub headers: Vec<(String, String)>,
+    pub status: u16,
+}
+impl Logged {
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(x, _)| x.eq_ignore_ascii_case(name))
+            .map(|(_, x)| x.as_str())
+    }
+}
+
+struct Response {
+    status: u16,
+    headers: Vec<(String, String)>,
+    body: Vec<u8>,
+}
+impl Response {
+    fn new(status: u16, body: impl Into<Vec<u8>>) -> Self {
+        Self {
+            status,
+            headers: vec![],
+            body: body.into(),
+        }
+    }
+
+    fn error(status: u16, message: &str) -> Self {
+        Self::new(
+            status,
+            format!("{{\"message\":{message:?},\"code\":0}}").into_bytes(),
+        )
+    }
+}
+
+#[derive(Default)]
+struct State {
+    next_id: u64,
+    messages: BTreeMap<u64, Message>,
+    /// Responses returned before handling requests normally.
+    queued: VecDeque<Response>,
+    /// Largest request body accepted before answering with 413.
+    max_upload: Option<usize>,
+    /// Hang up without answering the next request after this long.
+    stall: Option<Duration>,
+    /// Wait this long before answering every request, like a slow link.
+    latency: Option<Duration>,
+    log: Vec<Logged>,
+    /// Healthcheck pings with their body.
+    pings: Vec<(String, String)>,
+}
+
+pub struct Server {
+    addr: SocketAddr,
+    state: Arc<Mutex<State>>,
+}
+impl Server {
+    pub fn start() -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let state = Arc::new(Mutex::new(State {
+            next_id: 1000,
+            ..Default::default()
+        }));
+
+        let shared = state.clone();
+        std::thread::spawn(move || {
+            for stream in listener.incoming().flatten() {
+                let state = shared.clone();
+                std::thread::spawn(move || {
+                    let _ = handle(stream, addr, &state);
+                });
+            }
+        });
+
+        Self { addr, state }
+    }
+
+    pub fn webhook(&self) -> String {
+        format!("http://{}{PREFIX}", self.addr)
+    }
+
+    /// Answer the next request with `status` and a JSON `body`.
+    pub fn queue(&self, status: u16, body: &str) {
+        let mut response = Response::new(status, body);
+        if status == 429 {
+            // Discord rounds this up, the body has the precise value
+            response.headers.push(("Retry-After".into(), "1".into()));
+        }
+        self.state.lock().unwrap().queued.push_back(response);
+    }
+
+    /// Don't answer the next request, like a half-open connection would.
+    pub fn stall(&self, duration: Duration) {
+        self.state.lock().unwrap().stall = Some(duration);
+    }
+
+    /// Answer every request `duration` late, slowing backups down the same
+    /// way with every backend.
+    pub fn set_latency(&self, duration: Duration) {
+        self.state.lock().unwrap().latency = Some(duration);
+    }
+
+    /// Refuse uploads larger than `size` bytes like Discord does.
+    pub fn set_max_upload(&self, size: usize) {
+        self.state.lock().unwrap().max_upload = Some(size);
+    }
+
+    pub fn messages(&self) -> Vec<(u64, Message)> {
+        let state = self.state.lock().unwrap();
+        state
+            .messages
+            .iter()
+            .map(|(id, x)| (*id, x.clone()))
+            .collect()
+    }
+
+    /// Healthchecks.io style ping URL.
+    pub fn healthcheck(&self) -> String {
+        format!("http://{}/ping/0f4c3b2a", self.addr)
+    }
+
+    pub fn pings(&self) -> Vec<(String, String)> {
+        self.state.lock().unwrap().pings.clone()
+    }
+
+    pub fn requests(&self) -> Vec<Logged> {
+        self.state.lock().unwrap().log.clone()
+    }
+
+    /// Id of the message holding the backup manifest.
+    pub fn manifest(&self) -> Option<u64> {
+        self.manifests().into_iter().next().map(|(id, _)| id)
+    }
+
+    /// Messages holding backup manifests, with the manifest.
+    pub fn manifests(&self) -> Vec<(u64, String)> {
+        self.messages()
+            .into_iter()
+            .filter_map(|(id, x)| {
+                x.files
+                    .into_iter()
+                    .find(|(name, _)| name == "manifest.json")
+                    .map(|(_, data)| (id, String::from_utf8(data).unwrap()))
+            })
+            .collect()
+    }
+}
+
+fn handle(stream: TcpStream, addr: SocketAddr, state: &Mutex<State>) -> std::io::Result<()> {
+    stream.set_read_timeout(Some(Duration::from_secs(30)))?;
+    let mut reader = BufReader::new(stream.try_clone()?);
+
+    let mut line = String::new();
+    reader.read_line(&mut line)?;
+    let mut parts = line.split_whitespace();
+    let (Some(method), Some(target)) = (parts.next(), parts.next()) else {
+        return Ok(());
+    };
+    let (method, target) = (method.to_string(), target.to_string());
+
+    let mut headers = vec![];
+    loop {
+        let mut line = String::new();
+        reader.read_line(&mut line)?;
+        let line = line.trim_end();
+        if line.is_empty() {
+            break;
+        }
+        if let Some((name, value)) = line.split_once(':') {
+            headers.push((name.trim().to_string(), value.trim().to_string()));
+        }
+    }
+    let header = |name: &str| {
+        headers
+            .iter()
+            .find(|(x, _)| x.eq_ignore_ascii_case(name))
+            .map(|(_, x)| x.as_str())
+    };
+
+    let mut body = vec![];
+    if header("transfer-encoding").is_some_and(|x| x.eq_ignore_ascii_case("chunked")) {
+        loop {
+            let mut size = String::new();
+            reader.read_line(&mut size)?;
+            let size = usize::from_str_radix(size.trim(), 16).unwrap_or(0);
+            let mut chunk = vec![0; size + 2];
+            reader.read_exact(&mut chunk)?;
+            if size == 0 {
+                break;
+            }
+            body.extend_from_slice(&chunk[..size]);
+        }
+    } else if let Some(len) = header("content-length").and_then(|x| x.parse().ok()) {
+        body.resize(len, 0);
+        reader.read_exact(&mut body)?;
+    }
+
+    let latency = state.lock().unwrap().latency;
+    if let Some(x) = latency {
+        std::thread::sleep(x);
+    }
+
+    let stall = state.lock().unwrap().stall.take();
+    if let Some(x) = stall {
+        std::thread::sleep(x);
+        state.lock().unwrap().log.push(Logged {
+            method,
+            path: target,
+            headers,
+            status: 0,
+        });
+        return Ok(());
+    }
+
+    let response = {
+        let mut state = state.lock().unwrap();
+        let response = match state.queued.pop_front() {
+            Some(x) => x,
+            None => route(&mut state, addr, &method, &target, &headers, &body),
+        };
+        state.log.push(Logged {
+            method,
+            path: target,
+            headers: headers.clone(),
+            status: response.status,
+        });
+        response
+    };
+
+    let mut stream = reader.into_inner();
+    let mut head = format!(
+        "HTTP/1.1 {} Mock\r\nContent-Length: {}\r\nConnection: close\r\n",
+        response.status,
+        response.body.len()
+    );
+    if response.body.starts_with(b"{") {
+        head.push_str("Content-Type: application/json\r\n");
+    }
+    for (name, value) in &response.headers {
+        head.push_str(&format!("{name}: {value}\r\n"));
+    }
+    head.push_str("\r\n");
+    stream.write_all(head.as_bytes())?;
+    stream.write_all(&response.body)?;
+    stream.flush()
+}
+
+fn route(
+    state: &mut State,
+    addr: SocketAddr,
+    method: &str,
+    target: &str,
+    headers: &[(String, String)],
+    body: &[u8],
+) -> Response {
+    let (path, query) = target.split_once('?').unwrap_or((target, ""));
+
+    if path.starts_with("/ping/") {
+        state.pings.push((
+            target.to_string(),
+            String::from_utf8_lossy(body).into_owned(),
+        ));
+        return Response::new(200, "OK");
+    }
+
+    if let Some(rest) = path.strip_prefix("/attachments/") {
+        let mut parts = rest.split('/');
+        let (Some(id), Some(i)) = (
+            parts.next().and_then(|x| x.parse::<u64>().ok()),
+            parts.next().and_then(|x| x.parse::<usize>().ok()),
+        ) else {
+            return Response::error(404, "Unknown Attachment");
+        };
+        return match state.messages.get(&id).and_then(|x| x.files.get(i)) {
+            Some((_, data)) if method == "GET" => Response::new(200, data.clone()),
+            _ => Response::error(404, "Unknown Attachment"),
+        };
+    }
+
+    let Some(rest) = path.strip_prefix(PREFIX) else {
+        return Response::error(401, "Invalid Webhook Token");
+    };
+
+    match (method, rest.trim_end_matches('/')) {
+        ("POST", "") => {
+            if state.max_upload.is_some_and(|x| body.len() > x) {
+                return Response::error(413, "Request entity too large");
+            }
+            let Some(message) = multipart(headers, body) else {
+                return Response::error(400, "Cannot send an empty message");
+            };
+
+            state.next_id += 1;
+            let id = state.next_id;
+            let json = json(addr, id, &message);
+            state.messages.insert(id, message);
+
+            if query.split('&').any(|x| x == "wait=true") {
+                Response::new(200, json)
+            } else {
+                Response::new(204, vec![])
+            }
+        }
+        (method, rest) => {
+            let Some(id) = rest
+                .strip_prefix("/messages/")
+                .and_then(|x| x.parse::<u64>().ok())
+            else {
+                return Response::error(404, "Unknown Route");
+            };
+            if !state.messages.contains_key(&id) {
+                return Response::error(404, "Unknown Message");
+            }
+
+            match method {
+                "GET" => {}
+                "PATCH" => {
+                    let Some(content) = content(body) else {
+                        return Response::error(400, "Invalid Form Body");
+                    };
+                    state.messages.get_mut(&id).unwrap().content = content;
+                }
+                "DELETE" => {
+                    state.messages.remove(&id);
+                    return Response::new(204, vec![]);
+                }
+                _ => return Response::error(405, "Method Not Allowed"),
+            }
+            Response::new(200, json(addr, id, &state.messages[&id]))
+        }
+    }
+}
+
+/// Content of a JSON payload, `None` if it isn't valid JSON.
+fn content(data: &[u8]) -> Option<Option<String>> {
+    let json: JsonValue = std::str::from_utf8(data).ok()?.parse().ok()?;
+    let JsonValue::Object(json) = json else {
+        return None;
+    };
+    Some(match json.get("content") {
+        Some(JsonValue::String(x)) => Some(x.clone()),
+        _ => None,
+    })
+}
+
+fn multipart(headers: &[(String, String)], body: &[u8]) -> Option<Message> {
+    let boundary = headers
+        .iter()
+        .find(|(x, _)| x.eq_ignore_ascii_case("content-type"))?
+        .1
+        .split_once("boundary=")?
+        .1
+        .trim_matches('"');
+    let delimiter = format!("\r\n--{boundary}");
+
+    let mut message = Message::default();
+    let mut body = body.strip_prefix(&delimiter.as_bytes()[2..])?;
+    while !body.starts_with(b"--") {
+        body = body.strip_prefix(b"\r\n")?;
+        let end = body
+            .windows(delimiter.len())
+            .position(|x| x == delimiter.as_bytes())?;
+        let part = &body[..end];
+        body = &body[end + delimiter.len()..];
+
+        let split = part.windows(4).position(|x| x == b"\r\n\r\n")?;
+        let headers = String::from_utf8_lossy(&part[..split]);
+        let data = &part[split + 4..];
+
+        if let Some(name) = headers
+            .split("filename=\"")
+            .nth(1)
+            .and_then(|x| x.split('"').next())
+        {
+            message.files.push((name.to_string(), data.to_vec()));
+        } else if headers.contains("name=\"payload_json\"") {
+            message.content = content(data)?;
+        }
+    }
+
+    if message.content.is_none() && message.files.is_empty() {
+        return None;
+    }
+    Some(message)
+}
+
+fn json(addr: SocketAddr, id: u64, message: &Message) -> String {
+    let attachments = message
+        .files
+        .iter()
+        .enumerate()
+        .map(|(i, (name, data))| {
+            JsonValue::Object(HashMap::from([
+                ("filename".to_string(), JsonValue::String(name.clone())),
+                ("size".to_string(), JsonValue::Number(data.len() as f64)),
+                (
+                    "url".to_string(),
+                    JsonValue::String(format!("http://{addr}/attachments/{id}/{i}/{name}")),
+                ),
+            ]))
+        })
+        .collect();
+
+    JsonValue::Object(HashMap::from([
+        ("id".to_string(), JsonValue::String(id.to_string())),
+        (
+            "content".to_string(),
+            match &message.content {
+                Some(x) => JsonValue::String(x.clone()),
+                None => JsonValue::String(String::new()),
+            },
+        ),
+        ("attachments".to_string(), JsonValue::Array(attachments)),
+    ]))
+    .stringify()
+    .unwrap()
+}
+
+/// HTTP backends the binary was built with.
+pub const BACKENDS: &[&str] = &[
+    #[cfg(feature = "ureq")]
+    "ureq",
+    #[cfg(feature = "minreq")]
+    "minreq",
+];
+
+/// A fresh directory for a test to work in.
+pub fn workdir(name: &str) -> PathBuf {
+    let path = std::env::temp_dir().join(format!(
+        "discord-backup-util-test.{}.{name}",
+        std::process::id()
+    ));
+    let _ = fs::remove_dir_all(&path);
+    fs::create_dir_all(&path).unwrap();
+    path
+}
+
+/// Write `dir/backup_config` for `server`, staging in `dir/tmp` and backing
+/// up `dir/data` if it exists, followed by `extra`.
+pub fn config(server: &Server, dir: &Path, extra: &str) -> PathBuf {
+    fs::create_dir_all(dir.join("tmp")).unwrap();
+    let mut config = format!(
+        "webhook {}\nevery 1 day\nblock-size 1\ntemp-dir {}\n",
+        server.webhook(),
+        dir.join("tmp").display()
+    );
+    if dir.join("data").exists() {
+        config += &format!("include {} as data\n", dir.join("data").display());
+    }
+    config += extra;
+
+    let path = dir.join("backup_config");
+    fs::write(&path, config).unwrap();
+    path
+}
+
+/// Fill `path` with a few files, including `size` bytes of incompressible
+/// data.
+pub fn sample_data(path: &Path, size: usize) {
+    fs::create_dir_all(path.join("nested/deeper")).unwrap();
+    fs::create_dir_all(path.join("empty")).unwrap();
+    fs::write(path.join("hello.txt"), "Hello, world!\n").unwrap();
+    fs::write(
+        path.join("nested/dump.sql"),
+        "INSERT INTO t VALUES (1);\n".repeat(2000),
+    )
+    .unwrap();
+
+    let mut state = 0x2545f4914f6cdd1du64;
+    let random: Vec<u8> = (0..size)
+        .map(|_| {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state as u8
+        })
+        .collect();
+    fs::write(path.join("nested/deeper/random.bin"), random).unwrap();
+}
+
+/// Panic unless both directories hold the same files.
+pub fn assert_same(expected: &Path, actual: &Path) {
+    let mut entries: Vec<_> = fs::read_dir(expected)
+        .unwrap()
+        .map(|x| x.unwrap().file_name())
+        .collect();
+    entries.sort();
+    let mut found: Vec<_> = fs::read_dir(actual)
+        .unwrap_or_else(|why| panic!("{actual:?}: {why}"))
+        .map(|x| x.unwrap().file_name())
+        .collect();
+    found.sort();
+    assert_eq!(entries, found, "{actual:?}");
+
+    for name in entries {
+        let (a, b) = (expected.join(&name), actual.join(&name));
+        if a.is_dir() {
+            assert_same(&a, &b);
+        } else {
+            assert!(fs::read(&a).unwrap() == fs::read(&b).unwrap(), "{b:?}");
+        }
+    }
+}
+
+pub struct Output {
+    pub status: ExitStatus,
+    pub stdout: String,
+    pub stderr: String,
+}
+
+/// Command running the binary in `dir`, with its output and job state going
+/// to files in `dir`.
+pub fn command(dir: &Path, args: &[&str]) -> Command {
+    let mut command = Command::new(env!("CARGO_BIN_EXE_discord-backup-util"));
+    command
+        .args(args)
+        .current_dir(dir)
+        .env("XDG_STATE_HOME", dir.join("state"))
+        .env_remove("STATE_DIRECTORY")
+        .stdin(Stdio::null())
+        .stdout(fs::File::create(dir.join("stdout.log")).unwrap())
+        .stderr(fs::File::create(dir.join("stderr.log")).unwrap());
+    command
+}
+
+/// Start the binary in `dir`.
+pub fn spawn(dir: &Path, args: &[&str]) -> Child {
+    command(dir, args).spawn().unwrap()
+}
+
+/// Wait for a process started with [spawn], killing it if it takes longer
+/// than a minute.
+pub fn wait(dir: &Path, mut child: Child) -> Output {
+    let started = Instant::now();
+    let status = loop {
+        if let Some(x) = child.try_wait().unwrap() {
+            break x;
+        }
+        if started.elapsed() > Duration::from_secs(60) {
+            let _ = child.kill();
+            panic!(
+                "timed out\n\n{}{}",
+                fs::read_to_string(dir.join("stdout.log")).unwrap_or_default(),
+                fs::read_to_string(dir.join("stderr.log")).unwrap_or_default()
+            );
+        }
+        std::thread::sleep(Duration::from_millis(20));
+    };
+
+    Output {
+        status,
+        stdout: fs::read_to_string(dir.join("stdout.log")).unwrap(),
+        stderr: fs::read_to_string(dir.join("stderr.log")).unwrap(),
+    }
+}
+
+/// Run the binary in `dir` until it exits.
+pub fn run(dir: &Path, args: &[&str]) -> Output {
+    wait(dir, spawn(dir, args))
+}
+
+/// Wait up to a minute for `condition` to hold.
+pub fn wait_for(what: &str, condition: impl Fn() -> bool) {
+    let started = Instant::now();
+    while !condition() {
+        assert!(
+            started.elapsed() < Duration::from_secs(60),
+            "timed out waiting for {what}"
+        );
+        std::thread::sleep(Duration::from_millis(20));
+    }
+}
@@ -0,0 +1,87 @@
+#![cfg(unix)]
+
+mod common;
+
+use std::{fs, os::unix::fs::PermissionsExt, time::Duration};
+
+use common::{config, run, sample_data, spawn, wait, wait_for, workdir, Server};
+
+#[test]
+fn daemon_is_controlled_over_socket() {
+    let server = Server::start();
+    let dir = workdir("control");
+    sample_data(&dir.join("data"), 1_200_000);
+    let socket = dir.join("control.sock");
+    let config = config(
+        &server,
+        &dir,
+        &format!("name job\ncontrol-socket {}\n", socket.display()),
+    );
+    let config = config.to_string_lossy().into_owned();
+
+    // Keep the daemon's output apart from the ctl runs
+    let daemon_dir = dir.join("daemon");
+    fs::create_dir(&daemon_dir).unwrap();
+    let mut daemon = spawn(&daemon_dir, &[&config]);
+    wait_for("the first backup", || server.manifests().len() == 1);
+    let mode = fs::metadata(&socket).unwrap().permissions().mode();
+    assert_eq!(mode & 0o777, 0o600, "{mode:o}");
+    let ctl = |args: &[&str]| {
+        let mut all = vec!["ctl"];
+        all.extend(args);
+        all.push(&config);
+        run(&dir, &all)
+    };
+    wait_for("the daemon to go idle", || {
+        ctl(&["status"]).stdout.contains("phase: idle")
+    });
+
+    let output = ctl(&["status"]);
+    assert!(output.status.success(), "{}", output.stdout);
+    assert!(output.stdout.contains("job: job"), "{}", output.stdout);
+    assert!(
+        output.stdout.contains("last run: succeeded"),
+        "{}",
+        output.stdout
+    );
+    assert!(output.stdout.contains("(in 23h 59m)"), "{}", output.stdout);
+    assert!(ctl(&["next"]).stdout.contains("(in 23h 59m)"));
+
+    let output = ctl(&["trigger", "other"]);
+    assert!(!output.status.success());
+    assert!(output.stderr.contains("unknown job"), "{}", output.stderr);
+    let output = ctl(&["cancel"]);
+    assert!(!output.status.success());
+    assert!(output.stderr.contains("no backup is running"));
+
+    // Slow enough to catch the backup while it runs
+    server.set_latency(Duration::from_secs(1));
+    assert!(ctl(&["trigger", "job"]).status.success());
+    wait_for("the upload to start", || {
+        ctl(&["status"]).stdout.contains("progress: ")
+    });
+    let output = ctl(&["cancel"]);
+    assert!(output.status.success(), "{}", output.stdout);
+    wait_for("the backup to stop", || {
+        ctl(&["status"]).stdout.contains("last run: failed at")
+    });
+
+    let output = ctl(&["status"]);
+    assert!(
+        output.stdout.contains(": Backup interrupted"),
+        "{}",
+        output.stdout
+    );
+    assert!(output.stdout.contains("phase: idle"), "{}", output.stdout);
+    assert!(daemon.try_wait().unwrap().is_none());
+    assert_eq!(server.manifests().len(), 1);
+
+    assert_eq!(
+        unsafe { libc::kill(daemon.id() as libc::pid_t, libc::SIGTERM) },
+        0
+    );
+    assert!(wait(&daemon_dir, daemon).status.success());
+    assert!(!socket.exists());
+
+    fs::remove_dir_all(dir).unwrap();
+}
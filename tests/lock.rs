@@ -0,0 +1,142 @@
+mod common;
+
+#[cfg(unix)]
+use std::os::unix::fs::PermissionsExt;
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    process::Command,
+    time::Duration,
+};
+
+use common::{config, run, sample_data, spawn, wait, wait_for, workdir, Server};
+
+/// Where locks go with `temp-dir` pointing into `dir`.
+fn lock_dir(dir: &Path) -> PathBuf {
+    #[cfg(unix)]
+    return dir
+        .join("tmp")
+        .join(format!("discord-backup-util-{}", unsafe {
+            libc::geteuid()
+        }));
+    #[cfg(windows)]
+    return dir.join("tmp");
+}
+
+#[cfg(unix)]
+#[test]
+fn second_instance_fails_or_waits() {
+    let server = Server::start();
+    let dir = workdir("lock");
+    sample_data(&dir.join("data"), 1000);
+    let config = config(&server, &dir, "name job\n");
+    let config = config.to_string_lossy();
+
+    let daemon = spawn(&dir, &[&config]);
+    wait_for("the first backup", || server.manifests().len() == 1);
+
+    let output = run(&dir, &["--once", &config]);
+    assert!(!output.status.success());
+    assert!(
+        output.stderr.contains("is already running in process"),
+        "{}",
+        output.stderr
+    );
+    assert_eq!(server.manifests().len(), 1);
+
+    // The daemon's output file is shared, keep the once run apart
+    let once_dir = dir.join("once");
+    fs::create_dir(&once_dir).unwrap();
+    // The daemon keeps its config until reloaded
+    common::config(&server, &dir, "name job\nlock wait\n");
+    let once = spawn(&once_dir, &["--once", &config]);
+    wait_for("the once run to wait", || {
+        fs::read_to_string(once_dir.join("stdout.log"))
+            .unwrap()
+            .contains("waiting for it to finish")
+    });
+    std::thread::sleep(Duration::from_millis(500));
+    assert_eq!(server.manifests().len(), 1);
+
+    assert_eq!(
+        unsafe { libc::kill(daemon.id() as libc::pid_t, libc::SIGTERM) },
+        0
+    );
+    assert!(wait(&dir, daemon).status.success());
+    let output = wait(&once_dir, once);
+    assert!(output.status.success(), "{}", output.stdout);
+    assert_eq!(server.manifests().len(), 2);
+
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn lock_of_dead_process_is_recovered() {
+    let server = Server::start();
+    let dir = workdir("stale-lock");
+    sample_data(&dir.join("data"), 1000);
+    let config = config(&server, &dir, "name job\n");
+    let config = config.to_string_lossy();
+
+    let mut dead = Command::new(env!("CARGO_BIN_EXE_discord-backup-util"))
+        .arg("--keygen")
+        .stdout(std::process::Stdio::null())
+        .spawn()
+        .unwrap();
+    dead.wait().unwrap();
+    let lock = lock_dir(&dir).join("discord-backup-util-job-job.lock");
+    fs::create_dir_all(lock.parent().unwrap()).unwrap();
+    #[cfg(unix)]
+    fs::set_permissions(lock.parent().unwrap(), fs::Permissions::from_mode(0o700)).unwrap();
+    fs::write(&lock, dead.id().to_string()).unwrap();
+
+    for _ in 0..2 {
+        let output = run(&dir, &["--once", &config]);
+        assert!(output.status.success(), "{}", output.stdout);
+    }
+    assert_eq!(server.manifests().len(), 2);
+
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[cfg(unix)]
+#[test]
+fn planted_locks_are_refused() {
+    let server = Server::start();
+    let dir = workdir("planted-lock");
+    sample_data(&dir.join("data"), 1000);
+    let config = config(&server, &dir, "name job\n");
+    let config = config.to_string_lossy();
+    let target = dir.join("target");
+    fs::write(&target, "precious").unwrap();
+
+    // Others could have put something into a dir they can write to
+    let lock_dir = lock_dir(&dir);
+    fs::create_dir(&lock_dir).unwrap();
+    fs::set_permissions(&lock_dir, fs::Permissions::from_mode(0o777)).unwrap();
+    std::os::unix::fs::symlink(&target, lock_dir.join("discord-backup-util-job-job.lock")).unwrap();
+
+    let output = run(&dir, &["--once", &config]);
+    assert!(!output.status.success());
+    assert!(
+        output
+            .stderr
+            .contains("is not a directory only we can access"),
+        "{}",
+        output.stderr
+    );
+
+    // Even in a dir of ours, symlinks aren't followed
+    fs::set_permissions(&lock_dir, fs::Permissions::from_mode(0o700)).unwrap();
+    let output = run(&dir, &["--once", &config]);
+    assert!(!output.status.success());
+    assert!(
+        output.stderr.contains("failed to open lock file"),
+        "{}",
+        output.stderr
+    );
+    assert_eq!(fs::read_to_string(&target).unwrap(), "precious");
+    assert!(server.manifests().is_empty());
+
+    fs::remove_dir_all(dir).unwrap();
+}
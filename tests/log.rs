@@ -0,0 +1,126 @@
+mod common;
+
+use std::fs;
+
+use common::{config, run, sample_data, workdir, Server};
+use tinyjson::JsonValue;
+
+/// `(level, message)` of every line, which all have to be JSON objects.
+fn json_lines(text: &str) -> Vec<(String, String)> {
+    text.lines()
+        .map(|line| {
+            let Ok(JsonValue::Object(x)) = line.parse::<JsonValue>() else {
+                panic!("not a JSON object: {line}");
+            };
+            assert!(matches!(&x["time"], JsonValue::String(_)), "{line}");
+            match (&x["level"], &x["message"]) {
+                (JsonValue::String(level), JsonValue::String(message)) => {
+                    (level.clone(), message.clone())
+                }
+                _ => panic!("missing level or message: {line}"),
+            }
+        })
+        .collect()
+}
+
+#[test]
+fn warnings_go_to_stderr() {
+    let server = Server::start();
+    let dir = workdir("log-streams");
+    sample_data(&dir.join("data"), 1000);
+    server.queue(
+        429,
+        r#"{"message": "You are being rate limited.", "retry_after": 0.1, "global": false}"#,
+    );
+
+    let output = run(
+        &dir,
+        &["--once", &config(&server, &dir, "").to_string_lossy()],
+    );
+    assert!(output.status.success(), "{}", output.stderr);
+    assert!(
+        output.stderr.contains("WARN  Rate limited"),
+        "{}",
+        output.stderr
+    );
+    assert!(!output.stdout.contains("Rate limited"), "{}", output.stdout);
+    assert!(
+        output
+            .stdout
+            .contains("INFO  Backup completed successfully"),
+        "{}",
+        output.stdout
+    );
+    // Debug messages are hidden by default
+    assert!(!output.stdout.contains("Added file"), "{}", output.stdout);
+
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn json_to_stdout_and_file() {
+    let server = Server::start();
+    let dir = workdir("log-json");
+    sample_data(&dir.join("data"), 1000);
+    let file = dir.join("backup.log");
+
+    let output = run(
+        &dir,
+        &[
+            "--log-level",
+            "debug",
+            "--log-file",
+            &file.to_string_lossy(),
+            "--once",
+            &config(&server, &dir, "log-format json\nlog-level warn\n").to_string_lossy(),
+        ],
+    );
+    assert!(output.status.success(), "{}", output.stdout);
+    assert_eq!(output.stderr, "");
+
+    let lines = json_lines(&output.stdout);
+    assert!(
+        lines
+            .iter()
+            .any(|(level, message)| level == "debug" && message.starts_with("Added file")),
+        "{}",
+        output.stdout
+    );
+    assert!(lines.iter().all(|(level, _)| level != "trace"));
+    assert_eq!(json_lines(&fs::read_to_string(&file).unwrap()), lines);
+
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn log_file_is_rotated() {
+    let server = Server::start();
+    let dir = workdir("log-rotate");
+    sample_data(&dir.join("data"), 1000);
+    let file = dir.join("backup.log");
+    let extra = format!(
+        "log-file {}\nlog-level trace\nlog-max-size 1KiB\nlog-keep 2\n",
+        file.display()
+    );
+
+    let config = config(&server, &dir, &extra);
+    for _ in 0..3 {
+        let output = run(&dir, &["--once", &config.to_string_lossy()]);
+        assert!(output.status.success(), "{}", output.stdout);
+    }
+
+    for path in [
+        file.clone(),
+        dir.join("backup.log.1"),
+        dir.join("backup.log.2"),
+    ] {
+        let text = fs::read_to_string(&path).unwrap();
+        assert!(text.len() <= 1024, "{path:?}: {text}");
+    }
+    assert!(!dir.join("backup.log.3").exists());
+    assert!(fs::read_to_string(&file)
+        .unwrap()
+        .contains("Backup completed successfully"));
+
+    fs::remove_dir_all(dir).unwrap();
+}
@@ -0,0 +1,103 @@
+mod common;
+
+use std::{
+    fs,
+    io::{Read, Write},
+    net::{TcpListener, TcpStream},
+};
+
+use common::{config, run, sample_data, spawn, wait, wait_for, workdir, Server};
+
+#[test]
+fn textfile_keeps_counting_across_runs() {
+    let server = Server::start();
+    let dir = workdir("metrics-file");
+    sample_data(&dir.join("data"), 1_500_000);
+    let metrics = dir.join("backup.prom");
+    let extra = format!("name job\nmetrics-file {}\n", metrics.display());
+
+    server.queue(
+        429,
+        r#"{"message": "You are being rate limited.", "retry_after": 0.1, "global": false}"#,
+    );
+    let output = run(
+        &dir,
+        &["--once", &config(&server, &dir, &extra).to_string_lossy()],
+    );
+    assert!(output.status.success(), "{}", output.stdout);
+
+    let text = fs::read_to_string(&metrics).unwrap();
+    for line in [
+        "discord_backup_runs_total{job=\"job\"} 1\n",
+        "discord_backup_last_chunks{job=\"job\"} 2\n",
+        "discord_backup_http_rate_limited_total{job=\"job\"} 1\n",
+        "discord_backup_http_retries_total{job=\"job\"} 1\n",
+        "# TYPE discord_backup_last_success_timestamp_seconds gauge\n",
+    ] {
+        assert!(text.contains(line), "{line}\n{text}");
+    }
+    let success = text
+        .lines()
+        .find(|x| x.starts_with("discord_backup_last_success_timestamp_seconds{"))
+        .unwrap()
+        .to_string();
+
+    let config = config(&server, &dir, &format!("{extra}#!/bin/sh\nexit 1\n"));
+    let output = run(&dir, &["--once", &config.to_string_lossy()]);
+    assert!(!output.status.success());
+
+    let text = fs::read_to_string(&metrics).unwrap();
+    for line in [
+        "discord_backup_runs_total{job=\"job\"} 2\n",
+        "discord_backup_failures_total{job=\"job\",stage=\"script\"} 1\n",
+        "discord_backup_http_rate_limited_total{job=\"job\"} 1\n",
+        &format!("{success}\n"),
+    ] {
+        assert!(text.contains(line), "{line}\n{text}");
+    }
+
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[cfg(unix)]
+#[test]
+fn metrics_are_served() {
+    let server = Server::start();
+    let dir = workdir("metrics-listen");
+    sample_data(&dir.join("data"), 1000);
+    let address = TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap();
+    let config = config(
+        &server,
+        &dir,
+        &format!("name job\nmetrics-listen {address}\n"),
+    );
+
+    let daemon = spawn(&dir, &[&config.to_string_lossy()]);
+    wait_for("the first backup", || server.manifests().len() == 1);
+
+    let get = |path: &str| {
+        let mut stream = TcpStream::connect(address).unwrap();
+        write!(stream, "GET {path} HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        response
+    };
+    wait_for("the run to be recorded", || {
+        get("/metrics").contains("discord_backup_runs_total{job=\"job\"} 1\n")
+    });
+    let response = get("/metrics");
+    assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{response}");
+    assert!(response.contains("discord_backup_last_archive_bytes{job=\"job\"} "));
+    assert!(get("/").starts_with("HTTP/1.1 404 Not Found\r\n"));
+
+    assert_eq!(
+        unsafe { libc::kill(daemon.id() as libc::pid_t, libc::SIGTERM) },
+        0
+    );
+    assert!(wait(&dir, daemon).status.success());
+
+    fs::remove_dir_all(dir).unwrap();
+}
@@ -0,0 +1,130 @@
+#![cfg(unix)]
+
+mod common;
+
+use std::{
+    fs,
+    os::unix::net::UnixDatagram,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    thread::JoinHandle,
+    time::Duration,
+};
+
+use common::{command, config, sample_data, wait, workdir, Server, BACKENDS};
+
+/// Collects the messages sent to a fake systemd socket until `done` is set.
+fn listen(dir: &Path) -> (PathBuf, Arc<AtomicBool>, JoinHandle<Vec<String>>) {
+    let socket_path = dir.join("notify.sock");
+    let socket = UnixDatagram::bind(&socket_path).unwrap();
+    socket
+        .set_read_timeout(Some(Duration::from_millis(100)))
+        .unwrap();
+    let done = Arc::new(AtomicBool::new(false));
+    let listener = std::thread::spawn({
+        let done = done.clone();
+        move || {
+            let mut messages = vec![];
+            let mut buffer = [0u8; 4096];
+            loop {
+                match socket.recv(&mut buffer) {
+                    Ok(len) => messages.push(String::from_utf8_lossy(&buffer[..len]).into_owned()),
+                    Err(_) if done.load(Ordering::SeqCst) => break messages,
+                    Err(_) => (),
+                }
+            }
+        }
+    });
+    (socket_path, done, listener)
+}
+
+#[test]
+fn systemd_is_notified() {
+    let server = Server::start();
+    let dir = workdir("notify");
+    sample_data(&dir.join("data"), 2_100_000);
+    let (socket_path, done, listener) = listen(&dir);
+
+    let config = config(
+        &server,
+        &dir,
+        &format!(
+            "http-backend {}\n#!/bin/sh\necho \"$NOTIFY_SOCKET\" > {}\n",
+            BACKENDS[0],
+            dir.join("script.env").display(),
+        ),
+    );
+
+    let child = command(&dir, &["--once", &config.to_string_lossy()])
+        .env("NOTIFY_SOCKET", &socket_path)
+        .env("WATCHDOG_USEC", "10000000")
+        .spawn()
+        .unwrap();
+    let output = wait(&dir, child);
+    done.store(true, Ordering::SeqCst);
+    assert!(output.status.success(), "{}", output.stdout);
+
+    let messages = listener.join().unwrap();
+
+    assert_eq!(messages[0], "READY=1");
+    for status in [
+        "STATUS=Starting backup process...",
+        "STATUS=Compressing the archive...",
+        "STATUS=Uploading chunk 1/3",
+        "STATUS=Uploading chunk 3/3",
+        "STATUS=Backup completed successfully.",
+    ] {
+        assert!(messages.iter().any(|x| x == status), "{messages:?}");
+    }
+    assert!(messages.iter().any(|x| x == "WATCHDOG=1"), "{messages:?}");
+
+    // Scripts must not talk to systemd on our behalf
+    assert_eq!(fs::read_to_string(dir.join("script.env")).unwrap(), "\n");
+
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn watchdog_is_fed_during_slow_scripts() {
+    let server = Server::start();
+    let dir = workdir("notify-watchdog");
+    let (socket_path, done, listener) = listen(&dir);
+
+    let config = config(
+        &server,
+        &dir,
+        &format!(
+            "http-backend {}\n#!/bin/sh\nsleep 3\necho hi > \"$BACKUP_DIR/file\"\n",
+            BACKENDS[0],
+        ),
+    );
+
+    let child = command(&dir, &["--once", &config.to_string_lossy()])
+        .env("NOTIFY_SOCKET", &socket_path)
+        .env("WATCHDOG_USEC", "1000000")
+        .spawn()
+        .unwrap();
+    let output = wait(&dir, child);
+    done.store(true, Ordering::SeqCst);
+    assert!(output.status.success(), "{}", output.stdout);
+
+    let messages = listener.join().unwrap();
+    let start = messages
+        .iter()
+        .position(|x| x == "STATUS=Backing up data...")
+        .unwrap();
+    let end = messages
+        .iter()
+        .position(|x| x == "STATUS=Compressing the archive...")
+        .unwrap();
+    let pings = messages[start..end]
+        .iter()
+        .filter(|x| *x == "WATCHDOG=1")
+        .count();
+    assert!(pings >= 3, "{messages:?}");
+
+    fs::remove_dir_all(dir).unwrap();
+}
@@ -0,0 +1,157 @@
+#![cfg(unix)]
+
+mod common;
+
+use std::{
+    fs,
+    time::{Duration, Instant},
+};
+
+use common::{config, sample_data, spawn, wait, wait_for, workdir, Server, BACKENDS};
+
+fn signal(child: &std::process::Child, signal: libc::c_int) {
+    assert_eq!(unsafe { libc::kill(child.id() as libc::pid_t, signal) }, 0);
+}
+
+#[test]
+fn sigterm_interrupts_backup() {
+    let server = Server::start();
+    let dir = workdir("sigterm");
+    sample_data(&dir.join("data"), 2_100_000);
+    let config = config(&server, &dir, &format!("http-backend {}\n", BACKENDS[0]));
+
+    server.set_latency(Duration::from_secs(1));
+
+    let child = spawn(&dir, &[&config.to_string_lossy()]);
+    wait_for("the first chunk", || {
+        server
+            .messages()
+            .iter()
+            .any(|(_, x)| x.files.iter().any(|(x, _)| x.starts_with("chunk_")))
+    });
+    signal(&child, libc::SIGTERM);
+
+    let output = wait(&dir, child);
+    assert!(output.status.success(), "{}", output.stdout);
+    assert!(output.stdout.contains("Backup interrupted, exiting"));
+
+    let messages = server.messages();
+    assert_eq!(messages[0].1.content.as_deref(), Some("Backup interrupted"));
+    let chunks = messages
+        .iter()
+        .filter(|(_, x)| x.files.iter().any(|(x, _)| x.starts_with("chunk_")))
+        .count();
+    assert!(chunks < 3, "{chunks} chunks were uploaded");
+    assert!(server.manifest().is_none());
+    // Only the dir holding the lock is left
+    let lock_dir = format!("discord-backup-util-{}", unsafe { libc::geteuid() });
+    let leftovers: Vec<_> = fs::read_dir(dir.join("tmp"))
+        .unwrap()
+        .map(|x| x.unwrap().file_name().into_string().unwrap())
+        .filter(|x| *x != lock_dir)
+        .collect();
+    assert!(leftovers.is_empty(), "{leftovers:?}");
+
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn sighup_reloads_and_sigusr1_triggers() {
+    let server = Server::start();
+    let dir = workdir("sighup");
+    sample_data(&dir.join("data"), 1000);
+    let write = |name: &str| config(&server, &dir, &format!("name {name}\n"));
+    let config = write("before");
+
+    let child = spawn(&dir, &[&config.to_string_lossy()]);
+    wait_for("the first backup", || server.manifests().len() == 1);
+
+    write("after");
+    signal(&child, libc::SIGHUP);
+    wait_for("the reload", || {
+        fs::read_to_string(dir.join("stdout.log"))
+            .unwrap()
+            .contains("Reloaded config")
+    });
+    signal(&child, libc::SIGUSR1);
+    wait_for("the second backup", || server.manifests().len() == 2);
+
+    signal(&child, libc::SIGTERM);
+    let output = wait(&dir, child);
+    assert!(output.status.success(), "{}", output.stdout);
+
+    let manifests = server.manifests();
+    assert!(manifests[0].1.contains(r#""job":"before""#));
+    assert!(manifests[1].1.contains(r#""job":"after""#));
+
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn first_backup_runs_at_startup() {
+    let server = Server::start();
+    let dir = workdir("startup");
+    sample_data(&dir.join("data"), 1000);
+    let config = config(&server, &dir, "");
+
+    // Like it always has, instead of waiting a day first
+    let child = spawn(&dir, &[&config.to_string_lossy()]);
+    wait_for("the first backup", || server.manifests().len() == 1);
+
+    signal(&child, libc::SIGTERM);
+    assert!(wait(&dir, child).status.success());
+
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn sigterm_stops_retrying_during_outage() {
+    let server = Server::start();
+    let dir = workdir("outage");
+    sample_data(&dir.join("data"), 1000);
+    let config = config(&server, &dir, "");
+    for _ in 0..100 {
+        server.queue(500, r#"{"message": "Internal Server Error"}"#);
+    }
+
+    let child = spawn(&dir, &[&config.to_string_lossy()]);
+    wait_for("the first failure", || {
+        fs::read_to_string(dir.join("stderr.log"))
+            .unwrap()
+            .contains("request failed")
+    });
+    signal(&child, libc::SIGTERM);
+    let output = wait(&dir, child);
+    assert!(output.status.success(), "{}", output.stderr);
+    assert!(output.stdout.contains("Backup interrupted, exiting"));
+    assert!(server.messages().is_empty());
+
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn sigterm_cuts_rate_limit_waits_short() {
+    let server = Server::start();
+    let dir = workdir("long-rate-limit");
+    sample_data(&dir.join("data"), 1000);
+    let config = config(&server, &dir, "");
+    server.queue(
+        429,
+        r#"{"message": "You are being rate limited.", "retry_after": 250.0}"#,
+    );
+
+    let child = spawn(&dir, &[&config.to_string_lossy()]);
+    wait_for("the rate limit", || {
+        fs::read_to_string(dir.join("stderr.log"))
+            .unwrap()
+            .contains("Rate limited")
+    });
+    let stopped = Instant::now();
+    signal(&child, libc::SIGTERM);
+    let output = wait(&dir, child);
+    assert!(stopped.elapsed() < Duration::from_secs(10));
+    assert!(output.status.success(), "{}", output.stderr);
+    assert!(output.stdout.contains("Backup interrupted, exiting"));
+
+    fs::remove_dir_all(dir).unwrap();
+}